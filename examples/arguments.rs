@@ -0,0 +1,34 @@
+// Arguments are passed in order and values are returned to the caller.
+//
+//     mossc --sysroot <sysroot> examples/arguments.rs
+//
+// Expected output:
+//
+//     BF: 7
+//     BF: 123
+//     BF: 5
+//     BF: 4
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+fn sub(a: usize, b: usize) -> usize {
+    a - b
+}
+
+fn digits(a: usize, b: usize, c: usize) -> usize {
+    a * 100 + b * 10 + c
+}
+
+fn swap(pair: (usize, usize)) -> (usize, usize) {
+    (pair.1, pair.0)
+}
+
+fn main() {
+    __out(sub(10, 3));
+    __out(digits(1, 2, 3));
+
+    let (x, y) = swap((4, 5));
+    __out(x);
+    __out(y);
+}
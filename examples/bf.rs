@@ -1,9 +1,9 @@
 
 // const x: usize = 0;
 
-// const TAPESIZE: usize = 2048;
+const TAPESIZE: usize = 2048;
 struct Tape {
-    tape: [usize; 2048],
+    tape: [usize; TAPESIZE],
     position: usize,
 }
 
@@ -32,17 +32,17 @@ fn devance(tape: &mut Tape) {
 }
 
 
-// const EXIT: usize = 0;
-// const GET: usize = 1;
-// const SET: usize = 2;
-// const INC: usize = 3;
-// const DEC: usize = 4;
-// const ADV: usize = 5;
-// const DEV: usize = 6;
-// const PRINT: usize = 7;
-// const OUT: usize = 8;
-// const JFF: usize = 9;
-// const JTB: usize = 10;
+const EXIT: usize = 0;
+const GET: usize = 1;
+const SET: usize = 2;
+const INC: usize = 3;
+const DEC: usize = 4;
+const ADV: usize = 5;
+const DEV: usize = 6;
+const PRINT: usize = 7;
+const OUT: usize = 8;
+const JFF: usize = 9;
+const JTB: usize = 10;
 
 fn __print(n: usize) {}
 fn __out(n: usize) {}
@@ -52,19 +52,7 @@ fn __met_merge_point(n: usize) {}
 
 fn main() {
 
-    let EXIT: usize = 0;
-    let GET: usize = 1;
-    let SET: usize = 2;
-    let INC: usize = 3;
-    let DEC: usize = 4;
-    let ADV: usize = 5;
-    let DEV: usize = 6;
-    let PRINT: usize = 7;
-    let OUT: usize = 8;
-    let JFF: usize = 9;
-    let JTB: usize = 10;
-
-    let mut tape = Tape { tape: [0; 2048], position: 0 };
+    let mut tape = Tape { tape: [0; TAPESIZE], position: 0 };
 
     let mut pc = 0;
 
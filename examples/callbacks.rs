@@ -0,0 +1,35 @@
+// Callbacks are called through the `Fn` traits: a fn item or a fn pointer
+// passed as a type parameter and a closure behind a reference to `Fn`.
+//
+//     mossc --sysroot <sysroot> examples/callbacks.rs
+//
+// Expected output:
+//
+//     BF: 6
+//     BF: 10
+//     BF: 13
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+fn apply<F: Fn(usize) -> usize>(f: F, n: usize) -> usize {
+    f(n)
+}
+
+fn apply_object(f: &Fn(usize) -> usize, n: usize) -> usize {
+    f(n)
+}
+
+fn double(n: usize) -> usize {
+    n * 2
+}
+
+fn main() {
+    __out(apply(double, 3));
+
+    let pointer: fn(usize) -> usize = double;
+    __out(apply(pointer, 5));
+
+    let offset = 10;
+    __out(apply_object(&|n| n + offset, 3));
+}
@@ -0,0 +1,42 @@
+// Casts between integers truncate or sign extend like in rustc.
+//
+//     mossc --sysroot <sysroot> examples/casts.rs
+//
+// Expected output:
+//
+//     BF: 44
+//     BF: 4294967295
+//     BF: 4294967240
+//     BF: 7
+//     BF: 18446744073709551614
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+fn u16_to_u8(x: u16) -> u8 {
+    x as u8
+}
+
+fn i8_to_i32(x: i8) -> i32 {
+    x as i32
+}
+
+fn u8_to_i8(x: u8) -> i8 {
+    x as i8
+}
+
+fn f64_to_i32(x: f64) -> i32 {
+    x as i32
+}
+
+fn i32_to_u64(x: i32) -> u64 {
+    x as u64
+}
+
+fn main() {
+    __out(u16_to_u8(300) as usize);
+    __out(i8_to_i32(-1) as u32 as usize);
+    __out(u8_to_i8(200) as i32 as u32 as usize);
+    __out(f64_to_i32(7.9) as usize);
+    __out(i32_to_u64(-2) as usize);
+}
@@ -0,0 +1,27 @@
+// With overflow checks a u8 overflows at 255, not at the width of the
+// host's integers.
+//
+//     mossc --sysroot <sysroot> examples/overflow.rs
+//
+// Expected output on stdout, with exit code 101:
+//
+//     BF: 255
+//     0 traces generated
+//
+// and on stderr:
+//
+//     guest panicked at 'attempt to add with overflow'
+//     stack backtrace:
+//        0: add
+//        1: main
+
+fn __out(n: usize) {}
+
+fn add(a: u8, b: u8) -> u8 {
+    a + b
+}
+
+fn main() {
+    __out(add(200, 55) as usize);
+    __out(add(255, 1) as usize);
+}
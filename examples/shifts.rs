@@ -0,0 +1,33 @@
+// A shift only overflows if the amount is at least the width of the type,
+// `1i32 << 31` is fine.
+//
+//     mossc --sysroot <sysroot> examples/shifts.rs
+//
+// Expected output on stdout, with exit code 101:
+//
+//     BF: 2147483648
+//     BF: 255
+//     0 traces generated
+//
+// and on stderr:
+//
+//     guest panicked at 'attempt to shift left with overflow'
+//     stack backtrace:
+//        0: shl_i32
+//        1: main
+
+fn __out(n: usize) {}
+
+fn shl_i32(a: i32, b: u32) -> i32 {
+    a << b
+}
+
+fn shr_i8(a: i8, b: u32) -> i8 {
+    a >> b
+}
+
+fn main() {
+    __out(shl_i32(1, 31) as u32 as usize);
+    __out(shr_i8(-128, 7) as u8 as usize);
+    __out(shl_i32(1, 32) as u32 as usize);
+}
@@ -0,0 +1,30 @@
+// Recursing deeper than the stack limit stops the interpreter with an
+// error instead of exhausting the memory of the host.
+//
+//     MOSS_STACK_LIMIT=100 mossc --sysroot <sysroot> examples/stack_limit.rs
+//
+// Expected output on stdout, with exit code 1 and the pc of the call in `depth`:
+//
+//     BF: 50
+//
+// and on stderr:
+//
+//     error: guest stack overflow, more than 100 frames
+//       in depth at pc <pc>
+//       --> examples/stack_limit.rs:20:13: 20:25
+
+fn __out(n: usize) {}
+
+// main and 50 calls of depth fit into the limit, 1000 don't
+fn depth(n: usize) -> usize {
+    if n == 0 {
+        0
+    } else {
+        1 + depth(n - 1)
+    }
+}
+
+fn main() {
+    __out(depth(50));
+    __out(depth(1000));
+}
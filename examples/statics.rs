@@ -0,0 +1,31 @@
+// A static can refer to values created by its initializer, they stay alive
+// after the initializer returned.
+//
+//     mossc --sysroot <sysroot> examples/statics.rs
+//
+// Expected output:
+//
+//     BF: 6
+//     BF: 3
+//     BF: 6
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+static TABLE: &'static [usize] = &[1, 2, 3];
+
+fn sum(values: &[usize], len: usize) -> usize {
+    let mut sum = 0;
+    let mut i = 0;
+    while i < len {
+        sum += values[i];
+        i += 1;
+    }
+    sum
+}
+
+fn main() {
+    __out(sum(TABLE, 3));
+    __out(TABLE[2]);
+    __out(sum(TABLE, 3));
+}
@@ -0,0 +1,29 @@
+// An error inside a trace is reported at the source of the failing opcode.
+// The loop is traced after a few iterations, the last one reads past the
+// end of `a`.
+//
+//     mossc --sysroot <sysroot> examples/trace_error.rs
+//
+// Expected output on stderr, with exit code 1 and the last pc executed in
+// `main`:
+//
+//     error: out of bounds access at index 10 of an array of length 10
+//       in main at pc <pc>
+//       --> examples/trace_error.rs:24:25: 24:37
+
+fn __met_merge_point(pc: usize) {}
+fn __out(n: usize) {}
+
+fn main() {
+    let a = [1usize; 10];
+    let p = &a[0] as *const usize;
+
+    let mut sum = 0;
+    let mut i: isize = 0;
+    while i <= 10 {
+        __met_merge_point(0);
+        sum += unsafe { *p.offset(i) };
+        i += 1;
+    }
+    __out(sum);
+}
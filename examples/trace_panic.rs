@@ -0,0 +1,34 @@
+// A panic raised inside a trace unwinds like one raised by the
+// interpreter. The loop is traced after a few iterations, the last one
+// indexes past the end of `v`.
+//
+//     mossc --sysroot <sysroot> examples/trace_panic.rs
+//
+// Expected output on stdout, with exit code 101:
+//
+//     1 traces generated
+//
+// and on stderr:
+//
+//     guest panicked at 'index out of bounds: the len is 10 but the index is 10'
+//     stack backtrace:
+//        0: main
+
+fn __met_merge_point(pc: usize) {}
+fn __out(n: usize) {}
+
+fn main() {
+    let mut v = Vec::new();
+    while v.len() < 10 {
+        v.push(1usize);
+    }
+
+    let mut sum = 0;
+    let mut i = 0;
+    while i <= 10 {
+        __met_merge_point(0);
+        sum += v[i];
+        i += 1;
+    }
+    __out(sum);
+}
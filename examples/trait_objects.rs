@@ -0,0 +1,52 @@
+// Methods called on a trait object dispatch through its vtable, for a
+// `Box<Trait>` as well as for a `&Trait`.
+//
+//     mossc --sysroot <sysroot> examples/trait_objects.rs
+//
+// Expected output:
+//
+//     BF: 9
+//     BF: 10
+//     BF: 9
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+trait Shape {
+    fn area(&self) -> usize;
+}
+
+struct Square {
+    side: usize,
+}
+
+struct Rect {
+    width: usize,
+    height: usize,
+}
+
+impl Shape for Square {
+    fn area(&self) -> usize {
+        self.side * self.side
+    }
+}
+
+impl Shape for Rect {
+    fn area(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+fn area_of(shape: &Shape) -> usize {
+    shape.area()
+}
+
+fn main() {
+    let boxed: Box<Shape> = Box::new(Square { side: 3 });
+    __out(boxed.area());
+
+    let rect = Rect { width: 2, height: 5 };
+    __out(area_of(&rect));
+
+    __out(area_of(&*boxed));
+}
@@ -0,0 +1,36 @@
+// Without overflow checks arithmetic wraps at the width of the type.
+//
+//     mossc --sysroot <sysroot> -C debug-assertions=off examples/wrapping.rs
+//
+// Expected output:
+//
+//     BF: 0
+//     BF: 4294967295
+//     BF: 128
+//     BF: 0
+//     0 traces generated
+
+fn __out(n: usize) {}
+
+fn add_u8(a: u8, b: u8) -> u8 {
+    a + b
+}
+
+fn sub_u32(a: u32, b: u32) -> u32 {
+    a - b
+}
+
+fn add_i8(a: i8, b: i8) -> i8 {
+    a + b
+}
+
+fn mul_u16(a: u16, b: u16) -> u16 {
+    a * b
+}
+
+fn main() {
+    __out(add_u8(255, 1) as usize);
+    __out(sub_u32(0, 1) as usize);
+    __out(add_i8(127, 1) as u8 as usize);
+    __out(mul_u16(256, 256) as usize);
+}
@@ -10,6 +10,8 @@ use moss::mossc;
 use moss::mossc::interpret;
 use moss::mossc::Context;
 
+use std::io::{self, Write};
+
 use rustc::session::Session;
 use rustc_driver::{driver, CompilerCalls, Compilation};
 
@@ -31,7 +33,30 @@ impl<'a> CompilerCalls<'a> for MossCompilerCalls {
             let context = Context{tcx: tcx, map: &map};
 
             let (mut program, main, internal_funcs) = mossc::generate_bytecode(&context);
-            interpret::interpret(&mut program, main, tcx, map, &internal_funcs);
+            let stack_limit = stack_limit();
+            // MOSS_PRINT_TRACES prints each recorded trace
+            let print_traces = std::env::var_os("MOSS_PRINT_TRACES").is_some();
+            match interpret::interpret(&mut program, main, tcx, map, &internal_funcs, stack_limit,
+                                       print_traces) {
+                Ok(None) => {},
+                // like a native panic, the message goes to stderr
+                Ok(Some(panic)) => {
+                    let mut stderr = io::stderr();
+                    writeln!(stderr, "guest panicked at '{}'", panic.message).unwrap();
+                    writeln!(stderr, "stack backtrace:").unwrap();
+                    for (i, def_id) in panic.backtrace.iter().enumerate() {
+                        writeln!(stderr, "{:>4}: {}", i, tcx.item_path_str(*def_id)).unwrap();
+                    }
+                    std::process::exit(101);
+                },
+                Err(error) => {
+                    let mut stderr = io::stderr();
+                    writeln!(stderr, "error: {}", error.kind).unwrap();
+                    writeln!(stderr, "  in {} at pc {}", tcx.item_path_str(error.def_id), error.pc).unwrap();
+                    writeln!(stderr, "  --> {}", tcx.sess.codemap().span_to_string(error.span)).unwrap();
+                    std::process::exit(1);
+                },
+            }
         });
 
         control.after_analysis.stop = Compilation::Stop;
@@ -40,6 +65,16 @@ impl<'a> CompilerCalls<'a> for MossCompilerCalls {
     }
 }
 
+// MOSS_STACK_LIMIT sets the maximum number of guest frames
+fn stack_limit() -> usize {
+    match std::env::var("MOSS_STACK_LIMIT") {
+        Ok(limit) => limit.parse().unwrap_or_else(|_| {
+            writeln!(io::stderr(), "MOSS_STACK_LIMIT must be a number, got '{}'", limit).unwrap();
+            std::process::exit(1);
+        }),
+        Err(_) => interpret::DEFAULT_STACK_LIMIT,
+    }
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
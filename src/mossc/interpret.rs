@@ -9,35 +9,68 @@ use rustc::mir::repr::BinOp;
 use rustc::hir::def_id::DefId;
 use rustc::ty::TyCtxt;
 use rustc::util::nodemap::DefIdMap;
+use syntax::ast::{IntTy, UintTy, FloatTy};
+use syntax::parse::token::InternedString;
+use syntax::codemap::Span;
 
 
-use mossc::{Program, Function, OpCode, Guard};
+use mossc::{UnOp, Program, Function, OpCode, Guard, GuardValue, AssertKind, Instance, Vtable, CastTarget,
+            RangeKind};
 
 use std::ops::{Deref};
+use std::mem;
+use std::fmt;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 
 const HOT_LOOP: usize = 5;
 
+/// The number of guest frames if no other limit is given.
+pub const DEFAULT_STACK_LIMIT: usize = 10000;
+
 //XXX: Is it better to store Tuple/NamedTuple struct on the stack or
 // should we rather use references to them to keep the theme of 64 bit values.
 
-#[derive(Clone, Debug)]
-pub enum Address {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address<'tcx> {
     StackLocal(usize),
 
-    // (address in stack, address in vector)
-    StackComplex(usize, usize),
+    StaticFunc(Instance<'tcx>),
+
+    // a static, or a constant being copied
+    Static(Instance<'tcx>),
+
+    // (function, promoted index)
+    Promoted(Instance<'tcx>, usize),
 
-    StaticFunc(DefId),
+    // id of a heap allocation
+    Heap(usize),
+
+    // (root, steps), a field of a tuple, struct or enum variant or an
+    // element of an array, e.g. `&mut self.tape[i]` is
+    // Path(StackLocal(self), [0, i]). The root is never a path itself.
+    Path(Box<Address<'tcx>>, Vec<usize>),
+}
+
+impl<'tcx> Address<'tcx> {
+    // the address of a field or element of the value at this address
+    fn project(self, step: usize) -> Address<'tcx> {
+        match self {
+            Address::Path(root, mut steps) => {
+                steps.push(step);
+                Address::Path(root, steps)
+            },
+            root => Address::Path(Box::new(root), vec![step]),
+        }
+    }
 }
 
 
 #[derive(Clone, Debug)]
-pub struct StackCell {
-    address: Address,
-    value: WrappedValue,
+pub struct StackCell<'tcx> {
+    address: Address<'tcx>,
+    value: WrappedValue<'tcx>,
 }
 
 // impl<'a, 'tcx> Deref for StackCell<'a, 'tcx> {
@@ -55,89 +88,232 @@ pub struct StackCell {
 // }
 
 #[derive(Clone, Debug)]
-pub enum StackData {
+pub enum StackData<'tcx> {
     None,
 
     // Most data is loaded via pointers.
     // let x = 1;
     // let y = 2;
 
-    Pointer(Address),
+    Pointer(Address<'tcx>),
 
-    Value(WrappedValue),
+    Value(WrappedValue<'tcx>),
 
     Frame(usize),
     ArgCount(usize),
 }
 
-impl StackData {
-    fn unwrap_value(&self) -> WrappedValue {
+impl<'tcx> StackData<'tcx> {
+    fn unwrap_value(&self) -> EvalResult<WrappedValue<'tcx>> {
         if let StackData::Value(ref value) = *self {
-            value.clone()
+            Ok(value.clone())
         } else {
-            panic!("expected Value found {:?}", self);
+            type_confusion("Value", self)
         }
     }
 
-    fn unwrap_address(&self) -> Address {
+    fn unwrap_address(&self) -> EvalResult<Address<'tcx>> {
         if let StackData::Pointer(ref address) = *self {
-            address.clone()
+            Ok(address.clone())
         } else {
-            panic!("expected Address found {:?}", self);
+            type_confusion("Address", self)
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub enum WrappedValue {
+#[derive(Clone, Debug, PartialEq)]
+pub enum WrappedValue<'tcx> {
     None,
     StackReference(usize),
+    I8(i8),
+    I16(i16),
+    I32(i32),
     I64(i64),
+    Isize(isize),
+    U8(u8),
+    U16(u16),
+    U32(u32),
     U64(u64),
     Usize(usize),
+    F32(f32),
+    F64(f64),
     Bool(bool),
-    Address(Address),
-    Tuple(WrappedTuple),
-    Array(Vec<WrappedValue>),
+    Char(char),
+    // a &str, which is also used as the &[u8] of its bytes
+    Str(InternedString),
+    Address(Address<'tcx>),
+    Tuple(WrappedTuple<'tcx>),
+    Enum(WrappedEnum<'tcx>),
+    Array(Vec<WrappedValue<'tcx>>),
+    // pointer to an unsized value
+    Fat(Address<'tcx>, Metadata<'tcx>),
+    // (address of the array, start, length), a reference to a slice
+    Slice(Address<'tcx>, usize, usize),
+    // owning pointer to a heap allocation, which is freed when it's dropped
+    Box(usize),
+    // a Box of an unsized value, e.g. Box<Trait> or Box<[T]>
+    FatBox(usize, Metadata<'tcx>),
     // NamedTuple(W_NamedTuple<'a, 'tcx>),
     // Function(&'a Function<'tcx>),
 }
 
-impl WrappedValue {
-    fn unwrap_usize(&self) -> usize {
+#[derive(Clone, Debug, PartialEq)]
+pub enum Metadata<'tcx> {
+    // length of a slice
+    Len(usize),
+    Vtable(Rc<Vtable<'tcx>>),
+}
+
+impl<'tcx> WrappedValue<'tcx> {
+    /// The value a constant opcode pushes onto the stack.
+    pub fn from_constant(opcode: &OpCode) -> WrappedValue<'tcx> {
+        match *opcode {
+            OpCode::SignedInteger(n, IntTy::I8) => WrappedValue::I8(n as i8),
+            OpCode::SignedInteger(n, IntTy::I16) => WrappedValue::I16(n as i16),
+            OpCode::SignedInteger(n, IntTy::I32) => WrappedValue::I32(n as i32),
+            OpCode::SignedInteger(n, IntTy::I64) => WrappedValue::I64(n),
+            OpCode::SignedInteger(n, IntTy::Is) => WrappedValue::Isize(n as isize),
+            OpCode::UnsignedInteger(n, UintTy::U8) => WrappedValue::U8(n as u8),
+            OpCode::UnsignedInteger(n, UintTy::U16) => WrappedValue::U16(n as u16),
+            OpCode::UnsignedInteger(n, UintTy::U32) => WrappedValue::U32(n as u32),
+            OpCode::UnsignedInteger(n, UintTy::U64) => WrappedValue::U64(n),
+            OpCode::UnsignedInteger(n, UintTy::Us) => WrappedValue::Usize(n as usize),
+            OpCode::Usize(size) => WrappedValue::Usize(size),
+            OpCode::Float(f, FloatTy::F32) => WrappedValue::F32(f as f32),
+            OpCode::Float(f, FloatTy::F64) => WrappedValue::F64(f),
+            OpCode::Bool(b) => WrappedValue::Bool(b),
+            OpCode::Char(c) => WrappedValue::Char(c),
+            OpCode::Str(ref s) => WrappedValue::Str(s.clone()),
+            _ => panic!("expected constant, got {:?}", opcode),
+        }
+    }
+
+    // two's complement representation of integers, signed integers are sign
+    // extended
+    fn to_bits(&self) -> Option<u64> {
+        use self::WrappedValue::*;
+
+        Some(match *self {
+            I8(i) => i as u64,
+            I16(i) => i as u64,
+            I32(i) => i as u64,
+            I64(i) => i as u64,
+            Isize(i) => i as u64,
+            U8(u) => u as u64,
+            U16(u) => u as u64,
+            U32(u) => u as u64,
+            U64(u) => u,
+            Usize(u) => u as u64,
+            Bool(b) => b as u64,
+            Char(c) => c as u64,
+            _ => return None,
+        })
+    }
+
+    // negative and too large amounts saturate, they overflow any integer
+    fn shift_amount(&self) -> EvalResult<u32> {
+        let bits = match self.to_bits() {
+            Some(bits) => bits,
+            None => return type_confusion("integer", self),
+        };
+        if bits > u32::max_value() as u64 {
+            Ok(u32::max_value())
+        } else {
+            Ok(bits as u32)
+        }
+    }
+
+    fn unwrap_usize(&self) -> EvalResult<usize> {
         if let WrappedValue::Usize(size) = *self {
-            size
+            Ok(size)
+        } else {
+            type_confusion("Usize", self)
+        }
+    }
+
+    // the fields of an enum variant are accessed like a tuple
+    // the metadata isn't needed to access the data behind a fat pointer
+    // and a box is dereferenced like any other pointer
+    fn thin(self) -> WrappedValue<'tcx> {
+        match self {
+            WrappedValue::Fat(address, _) => WrappedValue::Address(address),
+            WrappedValue::Box(id) |
+            WrappedValue::FatBox(id, _) => WrappedValue::Address(Address::Heap(id)),
+            other => other,
+        }
+    }
+
+    fn unwrap_bool(&self) -> EvalResult<bool> {
+        if let WrappedValue::Bool(b) = *self {
+            Ok(b)
         } else {
-            panic!("expected Usize got {:?}", self);
+            type_confusion("Bool", self)
         }
     }
 
-    fn unwrap_tuple(&mut self) -> &mut WrappedTuple {
-        if let WrappedValue::Tuple(ref mut tuple) = *self {
-            tuple
+    fn unwrap_tuple(&mut self) -> EvalResult<&mut WrappedTuple<'tcx>> {
+        match *self {
+            WrappedValue::Tuple(ref mut tuple) => Ok(tuple),
+            WrappedValue::Enum(ref mut enm) => Ok(&mut enm.data),
+            ref other => type_confusion("Tuple", other),
+        }
+    }
+
+    // a field of a tuple or enum variant or an element of an array
+    fn step_mut(&mut self, step: usize) -> EvalResult<&mut WrappedValue<'tcx>> {
+        match *self {
+            // raw pointers can point past the end of an array
+            WrappedValue::Array(ref mut array) => {
+                if step >= array.len() {
+                    return Err(ErrorKind::OutOfBounds(step as isize, array.len()));
+                }
+                Ok(&mut array[step])
+            },
+            WrappedValue::Tuple(ref mut tuple) => tuple.field(step),
+            WrappedValue::Enum(ref mut enm) => enm.data.field(step),
+            ref other => type_confusion("Tuple or Array", other),
+        }
+    }
+
+    fn unwrap_variant(&self) -> EvalResult<usize> {
+        if let WrappedValue::Enum(ref enm) = *self {
+            Ok(enm.variant)
         } else {
-            panic!("expected Tuple, got {:?}", self);
+            type_confusion("Enum", self)
         }
     }
 }
 
 // TODO: implement getter and setter for tuple
-#[derive(Clone, Debug)]
-pub struct WrappedTuple {
-    data: Vec<WrappedValue>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedTuple<'tcx> {
+    data: Vec<WrappedValue<'tcx>>,
+    // `Drop::drop` of the struct or enum, called by the drop glue
+    destructor: Option<Instance<'tcx>>,
 }
 
-impl WrappedTuple {
+impl<'tcx> WrappedTuple<'tcx> {
     fn with_size(size: usize) -> Self {
         let mut v = Vec::with_capacity(size);
         for _ in 0..size {
             v.push(WrappedValue::None);
         }
 
-        WrappedTuple { data: v}
+        WrappedTuple { data: v, destructor: None }
+    }
+
+    fn field(&mut self, idx: usize) -> EvalResult<&mut WrappedValue<'tcx>> {
+        let len = self.data.len();
+        self.data.get_mut(idx).ok_or(ErrorKind::OutOfBounds(idx as isize, len))
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrappedEnum<'tcx> {
+    variant: usize,
+    data: WrappedTuple<'tcx>,
+}
+
 
 // // [1, 2, 3]
 // struct WrappedArray {
@@ -157,7 +333,102 @@ impl WrappedTuple {
 // }
 
 
-type Trace<'a> = Vec<OpCode<'a>>;
+/// A guest panic which is unwinding the stack.
+#[derive(Clone, Debug)]
+pub struct Panic {
+    pub message: String,
+    // functions left while unwinding, innermost first
+    pub backtrace: Vec<DefId>,
+}
+
+/// A fault of the guest which the interpreter can't continue from, e.g. an
+/// opcode getting a value of the wrong type. Unlike a `Panic` it isn't
+/// unwound, the frames are left as they were when it happened.
+#[derive(Clone, Debug)]
+pub struct InterpreterError {
+    pub kind: ErrorKind,
+    // the function and pc of the failing opcode, inside a trace the pc is
+    // the last one the interpreter executed in that function
+    pub def_id: DefId,
+    pub pc: usize,
+    // the source of the failing opcode, also inside a trace
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum ErrorKind {
+    // (expected, found)
+    TypeConfusion(String, String),
+    // (index, length of the array)
+    OutOfBounds(isize, usize),
+    // a dangling pointer or one used for something it can't do
+    InvalidPointer(String),
+    UnsupportedOpcode(String),
+    // an opcode needs more values than there are on the stack
+    StackUnderflow,
+    // more frames than the stack limit
+    StackOverflow(usize),
+    // (expected, found) height of the stack after an initializer
+    UnbalancedStack(usize, usize),
+    // an opcode which needs a frame ran without one
+    NoFrame,
+    // unwinding without a panic in flight
+    NotUnwinding,
+    // the loop start of a trace which hasn't been recorded
+    UnknownTrace(usize),
+    // (message, message of the panic in flight) of a panic in a destructor
+    DoublePanic(String, String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::TypeConfusion(ref expected, ref found) => {
+                write!(f, "expected {}, found {}", expected, found)
+            },
+            ErrorKind::OutOfBounds(index, len) => {
+                write!(f, "out of bounds access at index {} of an array of length {}", index, len)
+            },
+            ErrorKind::InvalidPointer(ref message) => write!(f, "invalid pointer: {}", message),
+            ErrorKind::UnsupportedOpcode(ref opcode) => write!(f, "unsupported opcode {}", opcode),
+            ErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            ErrorKind::StackOverflow(limit) => {
+                write!(f, "guest stack overflow, more than {} frames", limit)
+            },
+            ErrorKind::UnbalancedStack(expected, found) => {
+                write!(f, "expected a stack of {} values, found {}", expected, found)
+            },
+            ErrorKind::NoFrame => write!(f, "no guest frame"),
+            ErrorKind::NotUnwinding => write!(f, "unwinding without a panic"),
+            ErrorKind::UnknownTrace(pc) => write!(f, "no trace recorded at {}", pc),
+            ErrorKind::DoublePanic(ref nested, ref message) => {
+                write!(f, "guest panicked while panicking: '{}' during '{}'", nested, message)
+            },
+        }
+    }
+}
+
+type EvalResult<T> = Result<T, ErrorKind>;
+
+fn type_confusion<T, V: fmt::Debug>(expected: &str, found: V) -> EvalResult<T> {
+    Err(ErrorKind::TypeConfusion(expected.to_string(), format!("{:?}", found)))
+}
+
+
+// the spans of a trace are those of the opcodes it was recorded from
+type Trace<'a> = Function<'a>;
+
+// The activation of a guest function. Its slots in `w_stack` start at `base`
+// with the return slot. `pc` is the opcode being executed, for callers it's
+// their call, so they continue after it when the callee returns.
+#[derive(Clone, Debug)]
+struct Frame<'a> {
+    def_id: DefId,
+    func: Rc<Function<'a>>,
+    pc: usize,
+    base: usize,
+    size: usize,
+}
 
 struct Interpreter<'p, 'a: 'p, 'cx: 'a> {
     program: &'p mut Program<'a, 'cx>,
@@ -168,125 +439,193 @@ struct Interpreter<'p, 'a: 'p, 'cx: 'a> {
     active_trace: Trace<'a>,
     //map pc to traces
     traces: BTreeMap<usize, Rc<Trace<'a>>>,
+    // the opcode of the trace being executed
+    trace_pc: usize,
+    // where the error being returned happened, set by the innermost loop
+    error_span: Option<Span>,
     // loader: &'a ModulesLoader<'a, 'cx>,
 
-    w_stack: WStack,
-    w_stack_pointer: usize,
-    w_stack_pointer_stack: Vec<usize>,
-    stack: Stack,
+    w_stack: WStack<'a>,
+    frames: Vec<Frame<'a>>,
+    // the maximum number of frames
+    stack_limit: usize,
+    // print each trace when it has been recorded
+    print_traces: bool,
+    stack: Stack<'a>,
+
+    // values of statics and constants, evaluated on first use
+    statics: HashMap<Instance<'a>, WrappedValue<'a>>,
+    // values borrowed by the promoted constants of each function
+    promoted: HashMap<(Instance<'a>, usize), WrappedValue<'a>>,
+
+    // live heap allocations, freed ones are removed
+    heap: HashMap<usize, WrappedValue<'a>>,
+    next_allocation: usize,
+
+    panic: Option<Panic>,
 }
 
-type Stack = Vec<StackData>;
-type WStack = Vec<WrappedValue>;
+type Stack<'a> = Vec<StackData<'a>>;
+type WStack<'a> = Vec<WrappedValue<'a>>;
 
 impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
-    fn new(program: &'p mut Program<'a, 'cx>, internals_map: &'p BTreeMap<DefId, String>) -> Self {
+    fn new(program: &'p mut Program<'a, 'cx>,
+           internals_map: &'p BTreeMap<DefId, String>,
+           stack_limit: usize,
+           print_traces: bool) -> Self {
         Interpreter {
             program: program,
             internals_map: internals_map,
             stack: Stack::new(),
             w_stack: WStack::new(),
-            w_stack_pointer: 0,
+            frames: Vec::new(),
+            stack_limit: stack_limit,
+            print_traces: print_traces,
             trace_counter: BTreeMap::new(),
             is_tracing: false,
             loop_start: 0,
-            active_trace: Vec::new(),
+            active_trace: Trace::new(),
             traces: BTreeMap::new(),
-            w_stack_pointer_stack: Vec::new(),
+            trace_pc: 0,
+            error_span: None,
+            statics: HashMap::new(),
+            promoted: HashMap::new(),
+            heap: HashMap::new(),
+            next_allocation: 0,
+            panic: None,
         }
     }
 
-    fn run(&mut self, main: DefId) {
+    // Returns the panic the guest ended with, if any.
+    fn run(&mut self, main: Instance<'a>) -> Result<Option<Panic>, InterpreterError> {
         let main_func = self.program.get_func(main);
-        self.eval_func(main_func);
+        if let Err(kind) = self.eval_func(main.def_id, main_func.clone(), 0) {
+            // errors are returned right away, so the failing opcode is
+            // still the current one of the innermost frame
+            let (def_id, pc) = match self.frames.last() {
+                Some(frame) => (frame.def_id, frame.pc),
+                None => (main.def_id, 0),
+            };
+            let span = self.error_span.take().unwrap_or_else(|| main_func.span(0));
+            return Err(InterpreterError { kind: kind, def_id: def_id, pc: pc, span: span });
+        }
 
         println!("{} traces generated", self.traces.len());
         // println!("{:?}", self.traces);
+
+        Ok(self.panic.take())
     }
 
-    // fn deref(&mut self, address: Address) -> WrappedValue {
+    // fn deref(&mut self, address: Address) -> WrappedValue<'a> {
         // match address {
             // Address::StackLocal(idx) => self.w_stack[idx].clone(),
             // _ => unimplemented!()
         // }
     // }
 
-    fn to_value(&mut self, data: &StackData) -> WrappedValue {
+    fn to_value(&mut self, data: &StackData<'a>) -> EvalResult<WrappedValue<'a>> {
         match data {
-            &StackData::Value(ref v) => v.clone(),
-            &StackData::Pointer(Address::StackLocal(ref other)) => {
-                self.w_stack[*other].clone()
-            },
-            &StackData::Pointer(Address::StaticFunc(ref def_id)) => {
-                WrappedValue::Address(Address::StaticFunc(def_id.clone()))
+            &StackData::Value(ref v) => Ok(v.clone()),
+            &StackData::Pointer(Address::StaticFunc(instance)) => {
+                Ok(WrappedValue::Address(Address::StaticFunc(instance)))
             },
-            &StackData::Pointer(Address::StackComplex(a, b)) => {
-                let tuple = self.w_stack[a].unwrap_tuple();
-                tuple.data[b].clone()
-            }
-            _ => panic!("should not load interpreter level object {:?}", data)
+            &StackData::Pointer(ref address) => Ok(try!(self.value_at(address)).clone()),
+            _ => type_confusion("Value or Pointer", data),
         }
     }
 
-    fn pop_stack_value(&mut self) -> WrappedValue {
-        let something = self.stack.pop().unwrap();
+    fn pop(&mut self) -> EvalResult<StackData<'a>> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    fn peek(&self) -> EvalResult<&StackData<'a>> {
+        self.stack.last().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    fn pop_stack_value(&mut self) -> EvalResult<WrappedValue<'a>> {
+        let something = try!(self.pop());
         self.to_value(&something)
     }
 
-    fn eval_trace(&mut self, pc: usize) -> Option<Guard<'a>> {
-        let trace = self.traces.get(&pc).unwrap().clone();
+    fn pop_usize(&mut self) -> EvalResult<usize> {
+        try!(self.pop_stack_value()).unwrap_usize()
+    }
+
+    // the argument of a call of an internal, which is below its ArgCount
+    fn internal_arg(&self) -> EvalResult<StackData<'a>> {
+        let len = self.stack.len();
+        if len < 2 {
+            return Err(ErrorKind::StackUnderflow);
+        }
+        Ok(self.stack[len - 2].clone())
+    }
+
+    // Runs the trace recorded at `pc` until one of its guards fails.
+    fn eval_trace(&mut self, pc: usize) -> EvalResult<Option<Guard<'a>>> {
+        let trace = match self.traces.get(&pc) {
+            Some(trace) => trace.clone(),
+            None => return Err(ErrorKind::UnknownTrace(pc)),
+        };
+
+        let result = self.run_trace(&trace);
+        // the frames don't know which opcode of the trace failed
+        if result.is_err() && self.error_span.is_none() {
+            self.error_span = Some(trace.span(self.trace_pc));
+        }
+        result
+    }
 
-        let mut frame_size = None;
+    fn run_trace(&mut self, trace: &Trace<'a>) -> EvalResult<Option<Guard<'a>>> {
         loop {
-            for opcode in &*trace {
-                // println!("Trace Execute {:?} | SP {}", opcode, self.w_stack_pointer);
+            for (i, opcode) in trace.iter().enumerate() {
+                self.trace_pc = i;
+                // println!("Trace Execute {:?} | SP {}", opcode, self.frame().base);
                 match *opcode {
                     OpCode::Guard(ref guard) => {
-                        let failed = self.o_guard(guard);
-                        if failed {
-                            return Some(guard.clone());
+                        if !try!(self.o_guard(guard)) {
+                            return Ok(Some(guard.clone()));
                         }
                     },
 
                     OpCode::Debug(in_pc) => {},
 
-                    OpCode::StackFrame(stack_size) => {
-                        self.o_stackframe(stack_size);
-                        frame_size = Some(stack_size);
-                    },
+                    OpCode::Noop => {},
+
+                    OpCode::StackFrame(stack_size) => try!(self.o_stackframe(stack_size)),
 
                     OpCode::Use => {},
 
-                    OpCode::Call => {
-                        self.w_stack_pointer_stack.push(self.w_stack_pointer);
-                        self.w_stack_pointer += frame_size.unwrap();
-
-                        let wrapped_address = self.pop_stack_value();
-                        if let WrappedValue::Address(address) = wrapped_address {
-                            if let Address::StaticFunc(def_id) = address {
-
-                                if let Some(func_name) = self.internals_map.get(&def_id) {
-                                    match func_name.as_ref() {
-                                        "out" => {
-                                            let data = self.stack[self.stack.len()-2].clone();
-                                            let val = self.to_value(&data);
-                                            if let WrappedValue::Usize(n) = val {
-                                                // print!("{}", n as u8 as char);
-                                                println!("BF: {}", n);
-                                            }
-                                        },
-                                        "print" => {
-                                            let data = self.stack[self.stack.len()-2].clone();
-                                            let val = self.to_value(&data);
-                                            if let WrappedValue::Usize(n) = val {
-                                                print!("{}", n as u8 as char);
-                                            }
-                                        },
+                    // the frames are the same as without the trace, so the
+                    // interpreter can continue in any of them if a guard fails
+                    OpCode::TracedCall(call_pc) => {
+                        let data = try!(self.pop());
+                        let instance = try!(self.callee(&data));
+
+                        try!(self.frame_mut()).pc = call_pc;
+                        let callee = self.program.get_func(instance);
+                        let base = self.frame_end();
+                        try!(self.push_frame(instance.def_id, callee, base));
+
+                        if let Some(func_name) = self.internals_map.get(&instance.def_id) {
+                            match func_name.as_ref() {
+                                "out" => {
+                                    let data = try!(self.internal_arg());
+                                    let val = try!(self.to_value(&data));
+                                    if let WrappedValue::Usize(n) = val {
+                                        // print!("{}", n as u8 as char);
+                                        println!("BF: {}", n);
+                                    }
+                                },
+                                "print" => {
+                                    let data = try!(self.internal_arg());
+                                    let val = try!(self.to_value(&data));
+                                    if let WrappedValue::Usize(n) = val {
+                                        print!("{}", n as u8 as char);
+                                    }
+                                },
 
-                                        _ => {}
-                                    };
-                                }
-                            }
+                                _ => {}
+                            };
                         }
                     },
 
@@ -294,41 +633,68 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                         self.stack.push(StackData::ArgCount(size));
                     },
 
-                    OpCode::LoadFunc(defid) => {
-                        self.stack.push(StackData::Pointer(Address::StaticFunc(defid)));
-                    },
-
+                    OpCode::UNTUPLE(size) => try!(self.o_untuple(size)),
+                    OpCode::VIRTUAL(idx) => try!(self.o_virtual(idx)),
+                    OpCode::UNSIZE(ref metadata) => try!(self.o_unsize(metadata)),
 
-                    OpCode::RETURN => {
-                        // println!("{:?} {:?}", self.w_stack_pointer, stack_pointers);
-                        // let frame_size = stack_pointers.pop().unwrap();
-                        // self.w_stack_pointer -= frame_size;
-                        let old_size = self.w_stack_pointer;
-                        self.o_return();
-                        frame_size = Some(old_size - self.w_stack_pointer);
+                    OpCode::LoadFunc(instance) => {
+                        self.stack.push(StackData::Pointer(Address::StaticFunc(instance)));
                     },
 
-                    OpCode::RETURN_POINTER => {},
 
+                    OpCode::RETURN => try!(self.o_return()),
 
-                    OpCode::TUPLE(n) => self.o_tuple(n),
-                    OpCode::TUPLE_ASSIGN(idx) => self.o_tuple_assign(idx),
-                    OpCode::TUPLE_GET(idx) => self.o_tuple_get(idx),
-                    OpCode::TUPLE_SET(idx) => self.o_tuple_set(idx),
-
-                    OpCode::VEC(n) => self.o_vec(n),
-                    OpCode::Repeat(n) => self.o_repeat(n),
 
-                    OpCode::AssignIndex => self.o_assign_index(),
-                    OpCode::GetIndex => self.o_get_index(),
 
-                    OpCode::Len => self.o_len(),
+                    OpCode::TUPLE(n) => self.o_tuple(n),
+                    OpCode::VARIANT(variant, n) => self.o_variant(variant, n),
+                    OpCode::DESTRUCTOR(instance) => try!(self.o_destructor(instance)),
+                    OpCode::TUPLE_ASSIGN(idx) => try!(self.o_tuple_assign(idx)),
+                    OpCode::TUPLE_GET(idx) => try!(self.o_tuple_get(idx)),
+                    OpCode::TUPLE_SET(idx) => try!(self.o_tuple_set(idx)),
+
+                    OpCode::VEC(n) => try!(self.o_vec(n)),
+                    OpCode::Repeat(n) => try!(self.o_repeat(n)),
+
+                    OpCode::AssignIndex => try!(self.o_assign_index()),
+                    OpCode::GetIndex => try!(self.o_get_index()),
+
+                    OpCode::Len => try!(self.o_len()),
+                    OpCode::CONSTANT_INDEX(offset, from_end) => try!(self.o_constant_index(offset, from_end)),
+                    OpCode::SUBSLICE(from, to) => try!(self.o_subslice(from, to)),
+                    OpCode::SLICE_RANGE(kind) => try!(self.o_slice_range(kind)),
+                    OpCode::VEC_SLICE => try!(self.o_vec_slice()),
+
+                    OpCode::PTR_OFFSET => try!(self.o_ptr_offset()),
+                    OpCode::PTR_COPY => try!(self.o_ptr_copy()),
+                    OpCode::PTR_WRITE => try!(self.o_ptr_write()),
+                    OpCode::UNINIT => {
+                        self.stack.push(StackData::Value(WrappedValue::None));
+                    },
 
-                    OpCode::SignedInteger(n) => {
-                        self.stack.push(StackData::Value(WrappedValue::I64(n)));
+                    OpCode::DROP => try!(self.o_drop()),
+                    OpCode::BOX => self.o_box(),
+                    OpCode::MOVE => try!(self.o_move()),
+
+                    OpCode::VEC_NEW => self.o_vec_new(),
+                    OpCode::VEC_FROM_BOX => try!(self.o_vec_from_box()),
+                    OpCode::VEC_PUSH => try!(self.o_vec_push()),
+                    OpCode::VEC_POP => try!(self.o_vec_pop()),
+                    OpCode::VEC_LEN => try!(self.o_vec_len()),
+                    OpCode::VEC_INDEX => try!(self.o_vec_index()),
+                    OpCode::VEC_ITER => try!(self.o_vec_iter()),
+                    OpCode::ITER_NEXT => try!(self.o_iter_next()),
+
+                    OpCode::Static(instance) => try!(self.o_static(instance)),
+                    OpCode::StoreStatic(instance) => try!(self.o_store_static(instance)),
+                    OpCode::LoadConst(instance) => try!(self.o_load_const(instance)),
+                    OpCode::LoadPromoted(instance, index) => {
+                        try!(self.o_load_promoted(instance, index));
                     },
-                    OpCode::UnsignedInteger(n) => {
-                        self.stack.push(StackData::Value(WrappedValue::U64(n)));
+
+                    OpCode::SignedInteger(..) | OpCode::UnsignedInteger(..) | OpCode::Float(..) |
+                    OpCode::Char(_) | OpCode::Str(_) => {
+                        self.stack.push(StackData::Value(WrappedValue::from_constant(opcode)));
                     },
                     OpCode::Usize(size) => {
                         self.stack.push(StackData::Value(WrappedValue::Usize(size)));
@@ -337,127 +703,262 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                         self.stack.push(StackData::Value(WrappedValue::Bool(b)));
                     },
 
-                    OpCode::StoreLocal(idx) => self.o_store_local(idx),
-                    OpCode::LoadLocal(idx) => self.o_load_local(idx),
-                    OpCode::BINOP(op) => self.o_binop(op),
-                    OpCode::CBINOP(op) => self.o_cbinop(op),
+                    OpCode::StoreLocal(idx) => try!(self.o_store_local(idx)),
+                    OpCode::LoadLocal(idx) => try!(self.o_load_local(idx)),
+                    OpCode::BINOP(op) => try!(self.o_binop(op)),
+                    OpCode::UNOP(op) => try!(self.o_unop(op)),
+                    OpCode::CAST(target) => try!(self.o_cast(target)),
+                    OpCode::CBINOP(op) => try!(self.o_cbinop(op)),
 
-                    OpCode::BORROW(..) => {
-                        let address = self.stack.pop().unwrap().unwrap_address();
-                        self.stack.push(StackData::Value(
-                            WrappedValue::Address(address)))
+                    OpCode::Pop => {
+                        try!(self.pop());
                     },
 
-                    OpCode::DEREF => {
-                        let wrapped_target = self.pop_stack_value();
-                        if let WrappedValue::Address(target) = wrapped_target {
-                            match target {
-                                Address::StackLocal(_idx) => {
-                                    self.stack.push(StackData::Pointer(target));
-                                },
-                                _ => unimplemented!()
-                            }
-                        }  else {
-                            panic!("can't resolve {:?}", wrapped_target);
-                        }
-                    },
+                    OpCode::BORROW(..) => try!(self.o_borrow()),
+
+                    OpCode::DEREF => try!(self.o_deref()),
 
                     OpCode::DEREF_STORE => {
-                        let wrapped_target = self.pop_stack_value();
-                        let value = self.pop_stack_value();
+                        let wrapped_target = try!(self.pop_stack_value()).thin();
+                        let value = try!(self.pop_stack_value());
 
                         if let WrappedValue::Address(target) = wrapped_target {
-                            match target {
-                                Address::StackLocal(idx) => {
-                                    self.w_stack[idx] = value;
-                                }
-                                _ => unimplemented!()
-                            }
+                            try!(self.store(target, value));
                         } else {
-                            panic!("can't resolve {:?}", wrapped_target);
+                            return type_confusion("pointer", wrapped_target);
                         }
                     },
-                    _ => {
-                        println!("UNHANDLED {:?}", opcode);
-                    }
+                    _ => return Err(ErrorKind::UnsupportedOpcode(format!("{:?}", opcode))),
                 }
             }
         }
-        None
+        Ok(None)
+    }
+
+    // each opcode of the trace with the guest source it was recorded from
+    fn print_trace(&self, start: usize, trace: &Trace<'a>) {
+        let codemap = self.program.context.tcx.sess.codemap();
+        println!("trace at {}:", start);
+        for (i, opcode) in trace.iter().enumerate() {
+            println!("{:>4}: {:?} at {}", i, opcode, codemap.span_to_string(trace.span(i)));
+        }
     }
 
-    //aquire space on the stack ahead of the current stack pointer
-    fn o_stackframe(&mut self, func_stacksize: usize) {
+    //aquire space on the stack for the current frame
+    fn o_stackframe(&mut self, size: usize) -> EvalResult<()> {
+        let base = try!(self.frame()).base;
+        try!(self.frame_mut()).size = size;
 
         // aquire space on the stack
-        for _ in self.w_stack.len() .. self.w_stack_pointer + func_stacksize {
+        for _ in self.w_stack.len() .. base + size {
             self.w_stack.push(WrappedValue::None);
         }
 
+        // the arguments are pushed in order and follow the return slot
         if let Some(&StackData::ArgCount(n)) = self.stack.last() {
             self.stack.pop();
-            for i in 0..n {
-                let something = self.stack.pop().unwrap();
-                let val = self.to_value(&something);
-                self.w_stack[self.w_stack_pointer + i] = val;
+            for i in (0..n).rev() {
+                let something = try!(self.pop());
+                let val = try!(self.to_value(&something));
+                self.w_stack[base + 1 + i] = val;
             }
         } else {
             // println!("expected ArgCount, got {:?}", self.stack.last());
         }
+        Ok(())
     }
 
-    fn o_return(&mut self) {
-        // can't return from main
-        if self.w_stack_pointer != 0 {
-            let old_pointer = self.w_stack_pointer_stack.pop().unwrap();
-            self.w_stack_pointer = old_pointer;
+    // the caller assigns the returned value to the destination of the call
+    fn o_return(&mut self) -> EvalResult<()> {
+        let base = try!(self.frame()).base;
+        let value = mem::replace(&mut self.w_stack[base], WrappedValue::None);
+        self.stack.push(StackData::Value(value));
+        self.frames.pop();
+        Ok(())
+    }
+
+    // Deep recursion in the guest stops the interpreter instead of
+    // exhausting the memory of the host.
+    fn push_frame(&mut self, def_id: DefId, func: Rc<Function<'a>>, base: usize) -> EvalResult<()> {
+        if self.frames.len() == self.stack_limit {
+            return Err(ErrorKind::StackOverflow(self.stack_limit));
         }
+        self.frames.push(Frame { def_id: def_id, func: func, pc: 0, base: base, size: 0 });
+        Ok(())
+    }
+
+    fn frame(&self) -> EvalResult<&Frame<'a>> {
+        self.frames.last().ok_or(ErrorKind::NoFrame)
     }
 
-    fn o_guard(&mut self, guard: &Guard) -> bool {
-        let data = self.pop_stack_value();
-        if let WrappedValue::Bool(b) = data {
-            // println!("guard({}) == {}", expected, bool);
-            return b != guard.expected;
-            // if b != guard.expected {
-                // println!("recover to {:?}[{}]", guard.recovery, guard.pc);
-                // panic!("guard fail");
-            // }
+    fn frame_mut(&mut self) -> EvalResult<&mut Frame<'a>> {
+        self.frames.last_mut().ok_or(ErrorKind::NoFrame)
+    }
+
+    // the first slot behind the current frame
+    fn frame_end(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base + frame.size)
+    }
+
+    // After the frame has been left, execution continues in the caller,
+    // unless the frame was the one eval_func started with.
+    fn caller(&self, depth: usize) -> Option<(Rc<Function<'a>>, usize)> {
+        if self.frames.len() == depth {
+            None
         } else {
-            panic!("expected bool, got {:?}", data);
+            self.frames.last().map(|frame| (frame.func.clone(), frame.pc))
+        }
+    }
+
+    // Returns whether the guard holds. The checked value is only popped if
+    // it does, otherwise the opcode at the recovery point still needs it.
+    fn o_guard(&mut self, guard: &Guard<'a>) -> EvalResult<bool> {
+        if let GuardValue::NoPanic = guard.expected {
+            return Ok(self.panic.is_none());
+        }
+
+        let data = try!(self.peek()).clone();
+        let value = try!(self.to_value(&data));
+        let holds = match (&guard.expected, &value) {
+            (&GuardValue::Bool(expected), &WrappedValue::Bool(b)) => b == expected,
+            (&GuardValue::Variant(expected), &WrappedValue::Enum(ref enm)) => {
+                enm.variant == expected
+            },
+            (&GuardValue::Value(ref expected), value) => expected == value,
+            _ => return type_confusion(&format!("{:?}", guard.expected), &value),
+        };
+
+        if holds {
+            try!(self.pop());
         }
+        // else println!("recover to {:?}[{}]", guard.recovery, guard.pc);
+        Ok(holds)
     }
 
-    fn eval_func(&mut self, func: Rc<Function<'a>>) {
+    // Calls `func` with the arguments on the stack in a frame starting at
+    // `base` and runs until it returns. Calls in the guest don't recurse on
+    // the host, they push a frame and the loop continues in the callee.
+    fn eval_func(&mut self, def_id: DefId, func: Rc<Function<'a>>, base: usize) -> EvalResult<()> {
+        let result = self.run_func(def_id, func, base);
+        // unless it happened in a trace or a nested call, the error is at
+        // the current opcode of the innermost frame
+        if result.is_err() && self.error_span.is_none() {
+            self.error_span = self.frames.last().map(|frame| frame.func.span(frame.pc));
+        }
+        result
+    }
+
+    fn run_func(&mut self, def_id: DefId, func: Rc<Function<'a>>, base: usize) -> EvalResult<()> {
+        let depth = self.frames.len();
+        try!(self.push_frame(def_id, func.clone(), base));
 
         let mut func = func;
         let mut pc: usize = 0;
-        let mut func_stacksize = None;
 
         loop {
 
             let opcode = &func[pc].clone();
-            // println!("{:?} [{}]", self.w_stack[self.w_stack_pointer], self.w_stack_pointer);
-            // println!("[{}] --{:?}", self.w_stack.len(), self.w_stack_pointer, );
+            try!(self.frame_mut()).pc = pc;
             // println!("");
-            // println!("Execute {:?}| SP {}", opcode, self.w_stack_pointer);
+            // println!("Execute {:?}| SP {}", opcode, self.frame().base);
 
             if self.is_tracing {
                 match *opcode {
                     OpCode::JUMP(..) | OpCode::JUMP_REL(..) => {},
 
                     OpCode::JUMP_IF(..) | OpCode::JUMP_REL_IF(..) => {
-                        let wrapped = self.stack.last().unwrap().clone();
-                        let val = self.to_value(&wrapped);
+                        let wrapped = try!(self.peek()).clone();
+                        let val = try!(self.to_value(&wrapped));
                         if let WrappedValue::Bool(b) = val {
                             self.active_trace.push(
                                 OpCode::Guard(Guard {
-                                    expected: b,
+                                    expected: GuardValue::Bool(b),
                                     recovery: func.clone(),
                                     pc: pc,
                                 }));
                         } else {
-                            panic!("expected bool, got {:?}", val);
+                            return type_confusion("Bool", val);
+                        }
+                    },
+
+                    OpCode::SWITCH(..) | OpCode::SWITCH_REL(..) => {
+                        let wrapped = try!(self.peek()).clone();
+                        let variant = try!(try!(self.to_value(&wrapped)).unwrap_variant());
+                        self.active_trace.push(
+                            OpCode::Guard(Guard {
+                                expected: GuardValue::Variant(variant),
+                                recovery: func.clone(),
+                                pc: pc,
+                            }));
+                    },
+
+                    // a panic raised in the trace unwinds in the interpreter
+                    OpCode::UNWIND(..) | OpCode::UNWIND_REL(..) => {
+                        self.active_trace.push(
+                            OpCode::Guard(Guard {
+                                expected: GuardValue::NoPanic,
+                                recovery: func.clone(),
+                                pc: pc,
+                            }));
+                    },
+
+                    OpCode::ASSERT(expected, ref kind) => {
+                        self.active_trace.push(
+                            OpCode::Guard(Guard {
+                                expected: GuardValue::Bool(expected),
+                                recovery: func.clone(),
+                                pc: pc,
+                            }));
+
+                        if let AssertKind::BoundsCheck = *kind {
+                            self.active_trace.push(OpCode::Pop);
+                            self.active_trace.push(OpCode::Pop);
+                        }
+                    },
+
+                    // the inlined callee depends on the vtable of the receiver
+                    OpCode::VIRTUAL(idx) => {
+                        let instance = try!(self.virtual_callee(idx));
+                        self.active_trace.push(opcode.clone());
+                        self.active_trace.push(
+                            OpCode::Guard(Guard {
+                                expected: GuardValue::Value(
+                                    WrappedValue::Address(Address::StaticFunc(instance))),
+                                recovery: func.clone(),
+                                pc: pc + 1,
+                            }));
+                        self.active_trace.push(OpCode::LoadFunc(instance));
+                    },
+
+                    OpCode::SWITCH_INT(..) | OpCode::SWITCH_INT_REL(..) => {
+                        let wrapped = try!(self.peek()).clone();
+                        let val = try!(self.to_value(&wrapped));
+                        self.active_trace.push(
+                            OpCode::Guard(Guard {
+                                expected: GuardValue::Value(val),
+                                recovery: func.clone(),
+                                pc: pc,
+                            }));
+                    },
+
+                    // LoadFunc pushes the callee of a direct call, a fn
+                    // pointer is read from memory and the inlined callee
+                    // depends on it like for VIRTUAL
+                    OpCode::Call => {
+                        let wrapped = try!(self.peek()).clone();
+                        if let StackData::Pointer(Address::StaticFunc(_)) = wrapped {
+                            self.active_trace.push(OpCode::TracedCall(pc));
+                        } else {
+                            let instance = try!(self.callee(&wrapped));
+                            self.active_trace.push(
+                                OpCode::Guard(Guard {
+                                    expected: GuardValue::Value(
+                                        WrappedValue::Address(Address::StaticFunc(instance))),
+                                    recovery: func.clone(),
+                                    pc: pc,
+                                }));
+                            self.active_trace.push(OpCode::LoadFunc(instance));
+                            self.active_trace.push(OpCode::TracedCall(pc));
                         }
                     },
 
@@ -465,112 +966,150 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                         self.active_trace.push(opcode.clone());
                     }
                 }
+                self.active_trace.set_span(func.span(pc));
             }
 
             match *opcode {
                 OpCode::Noop => (),
 
-                OpCode::StackFrame(stack_size) => {
-                    func_stacksize = Some(stack_size);
-                    self.o_stackframe(stack_size);
-                },
+                OpCode::StackFrame(stack_size) => try!(self.o_stackframe(stack_size)),
 
                 OpCode::RETURN => {
-                    self.o_return();
-                    break
+                    try!(self.o_return());
+                    match self.caller(depth) {
+                        Some((caller, call_pc)) => {
+                            func = caller;
+                            pc = call_pc;
+                        },
+                        None => break,
+                    }
+                },
+
+                // end of a cleanup block, continue unwinding in the caller
+                OpCode::RESUME => {
+                    try!(self.o_unwind_frame());
+                    match self.caller(depth) {
+                        Some((caller, call_pc)) => {
+                            func = caller;
+                            pc = call_pc;
+                        },
+                        None => break,
+                    }
+                },
+
+                OpCode::UNWIND_REL(cleanup) => {
+                    if self.panic.is_some() {
+                        match cleanup {
+                            Some(n) => {
+                                pc = (pc as i32 + n) as usize;
+                                continue
+                            },
+                            None => {
+                                try!(self.o_unwind_frame());
+                                match self.caller(depth) {
+                                    Some((caller, call_pc)) => {
+                                        func = caller;
+                                        pc = call_pc;
+                                    },
+                                    None => break,
+                                }
+                            },
+                        }
+                    }
                 },
 
-                OpCode::RETURN_POINTER => {},
 
-                OpCode::LoadFunc(defid) => {
-                    self.stack.push(StackData::Pointer(Address::StaticFunc(defid)));
+                OpCode::LoadFunc(instance) => {
+                    self.stack.push(StackData::Pointer(Address::StaticFunc(instance)));
                 },
 
                 OpCode::ArgCount(size) => {
                     self.stack.push(StackData::ArgCount(size));
                 },
 
+                OpCode::UNTUPLE(size) => try!(self.o_untuple(size)),
+                OpCode::VIRTUAL(idx) => try!(self.o_virtual(idx)),
+                OpCode::UNSIZE(ref metadata) => try!(self.o_unsize(metadata)),
+
                 OpCode::Call => {
-                    // self.w_stack_pointer += func_stacksize.unwrap();
-
-                    // mark frame on stack
-                    // self.stack.push(StackData::Frame(self.w_stack_pointer));
-                    self.w_stack_pointer_stack.push(self.w_stack_pointer);
-                    self.w_stack_pointer += func_stacksize.unwrap();
-
-                    let wrapped_address = self.pop_stack_value();
-                    if let WrappedValue::Address(address) = wrapped_address {
-                        if let Address::StaticFunc(def_id) = address {
-
-                            if let Some(func_name) = self.internals_map.get(&def_id) {
-                                match func_name.as_ref() {
-                                    "out" => {
-                                        let data = self.stack[self.stack.len()-2].clone();
-                                        let val = self.to_value(&data);
-                                        if let WrappedValue::Usize(n) = val {
-                                            // print!("{}", n as u8 as char);
-                                            println!("BF: {}", n);
+                    let data = try!(self.pop());
+                    let instance = try!(self.callee(&data));
+
+                    let callee = self.program.get_func(instance);
+                    let base = self.frame_end();
+                    try!(self.push_frame(instance.def_id, callee.clone(), base));
+                    func = callee;
+                    pc = 0;
+
+                    if let Some(func_name) = self.internals_map.get(&instance.def_id) {
+                        match func_name.as_ref() {
+                            "out" => {
+                                let data = try!(self.internal_arg());
+                                let val = try!(self.to_value(&data));
+                                if let WrappedValue::Usize(n) = val {
+                                    // print!("{}", n as u8 as char);
+                                    println!("BF: {}", n);
+                                }
+                            },
+                            "print" => {
+                                let data = try!(self.internal_arg());
+                                let val = try!(self.to_value(&data));
+                                if let WrappedValue::Usize(n) = val {
+                                    // print!("{}", n as u8 as char);
+                                    print!("{}", n as u8 as char);
+                                }
+                            },
+                            "met_merge_point" => {
+                                let data = try!(self.internal_arg());
+                                let val = try!(self.to_value(&data));
+
+                                if let WrappedValue::Usize(in_pc) = val {
+                                    // println!("met_merge_point {:?}", in_pc);
+                                    if self.traces.contains_key(&in_pc) {
+                                        if let Some(guard) = try!(self.eval_trace(in_pc)) {
+                                            // continue in the frame of the guard
+                                            try!(self.frame_mut()).func = guard.recovery.clone();
+                                            func = guard.recovery;
+                                            pc = guard.pc;
+                                            // println!("FAILED IN {:?}", func[pc]);
+                                            continue;
                                         }
-                                    },
-                                    "print" => {
-                                        let data = self.stack[self.stack.len()-2].clone();
-                                        let val = self.to_value(&data);
-                                        if let WrappedValue::Usize(n) = val {
-                                            // print!("{}", n as u8 as char);
-                                            print!("{}", n as u8 as char);
+                                    } else if !self.is_tracing {
+                                        let count = {
+                                            let count = self.trace_counter.entry(in_pc).or_insert(0);
+                                            *count += 1;
+                                            *count
+                                        };
+                                        // println!("COUNT {:?} {}", in_pc, count);
+                                        if count > HOT_LOOP {
+                                            self.trace_counter.clear();
+                                            self.is_tracing = true;
+                                            self.loop_start = in_pc;
                                         }
-                                    },
-                                    "met_merge_point" => {
-                                        let data = self.stack[self.stack.len()-2].clone();
-                                        let val = self.to_value(&data);
-
-                                        if let WrappedValue::Usize(in_pc) = val {
-                                            // println!("met_merge_point {:?}", in_pc);
-                                            if self.traces.contains_key(&in_pc) {
-                                                if let Some(guard) = self.eval_trace(in_pc) {
-                                                    func = guard.recovery;
-                                                    pc = guard.pc;
-                                                    self.stack.push(StackData::Value(WrappedValue::Bool(!guard.expected)));
-                                                    // println!("FAILED IN {:?}", func[pc]);
-                                                    continue;
-                                                }
-                                            } else if !self.is_tracing {
-                                                let count = {
-                                                    let count = self.trace_counter.entry(in_pc).or_insert(0);
-                                                    *count += 1;
-                                                    *count
-                                                };
-                                                // println!("COUNT {:?} {}", in_pc, count);
-                                                if count > HOT_LOOP {
-                                                    self.trace_counter.clear();
-                                                    self.is_tracing = true;
-                                                    self.loop_start = in_pc;
-                                                }
-                                            } else {
-                                                // self.active_trace.push(OpCode::Debug(in_pc));
-                                                if in_pc == self.loop_start {
-                                                    // println!("trace finished");
-                                                    // println!("{:?}", self.active_trace);
-                                                    self.is_tracing = false;
-                                                    self.traces.insert(in_pc, Rc::new(self.active_trace.clone()));
-                                                }
+                                    } else {
+                                        // self.active_trace.push(OpCode::Debug(in_pc));
+                                        if in_pc == self.loop_start {
+                                            // println!("trace finished");
+                                            // println!("{:?}", self.active_trace);
+                                            self.is_tracing = false;
+                                            if self.print_traces {
+                                                self.print_trace(in_pc, &self.active_trace);
                                             }
-                                        } else {
-                                            panic!("expected usize got {:?}", val);
+                                            self.traces.insert(in_pc, Rc::new(self.active_trace.clone()));
                                         }
-                                    },
-                                    _ => unimplemented!()
+                                    }
+                                } else {
+                                    return type_confusion("Usize", val);
                                 }
-                            }
-
-                            let func = self.program.get_func(def_id);
-                            self.eval_func(func);
-                        } else {
-                            panic!("Expected func got {:?}", address);
+                            },
+                            _ => {
+                                let call = format!("Call of the internal {}", func_name);
+                                return Err(ErrorKind::UnsupportedOpcode(call));
+                            },
                         }
-                    } else {
-                        panic!("excpected address got {:?}", wrapped_address);
                     }
+
+                    continue
                 },
 
                 OpCode::JUMP_REL(n) => {
@@ -579,36 +1118,84 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                 },
 
                 OpCode::JUMP_REL_IF(n) => {
-                    // let data = self.stack.pop().unwrap();
-                    let data = self.pop_stack_value();
+                    // let data = try!(self.pop());
+                    let data = try!(self.pop_stack_value());
                     if let WrappedValue::Bool(b) = data {
                         if b {
                             pc = (pc as i32 + n) as usize;
                             continue;
                         }
                     } else {
-                        panic!("expected bool got {:?}", data);
+                        return type_confusion("Bool", data);
                     }
                 },
 
-                OpCode::TUPLE(n) => self.o_tuple(n),
-                OpCode::TUPLE_ASSIGN(idx) => self.o_tuple_assign(idx),
-                OpCode::TUPLE_GET(idx) => self.o_tuple_get(idx),
-                OpCode::TUPLE_SET(idx) => self.o_tuple_set(idx),
-
-                OpCode::VEC(n) => self.o_vec(n),
-                OpCode::Repeat(n) => self.o_repeat(n),
+                OpCode::SWITCH_REL(ref targets) => {
+                    let variant = try!(try!(self.pop_stack_value()).unwrap_variant());
+                    match targets.get(variant) {
+                        Some(&n) => pc = (pc as i32 + n) as usize,
+                        None => return Err(ErrorKind::OutOfBounds(variant as isize, targets.len())),
+                    }
+                    continue
+                },
 
-                OpCode::AssignIndex => self.o_assign_index(),
-                OpCode::GetIndex => self.o_get_index(),
+                OpCode::SWITCH_INT_REL(ref values, ref targets) => {
+                    let value = try!(self.pop_stack_value());
+                    // falls back to the otherwise target
+                    let idx = values.iter().position(|v| *v == value).unwrap_or(values.len());
+                    pc = (pc as i32 + targets[idx]) as usize;
+                    continue
+                },
 
-                OpCode::Len => self.o_len(),
+                OpCode::TUPLE(n) => self.o_tuple(n),
+                OpCode::VARIANT(variant, n) => self.o_variant(variant, n),
+                OpCode::DESTRUCTOR(instance) => try!(self.o_destructor(instance)),
+                OpCode::TUPLE_ASSIGN(idx) => try!(self.o_tuple_assign(idx)),
+                OpCode::TUPLE_GET(idx) => try!(self.o_tuple_get(idx)),
+                OpCode::TUPLE_SET(idx) => try!(self.o_tuple_set(idx)),
+
+                OpCode::VEC(n) => try!(self.o_vec(n)),
+                OpCode::Repeat(n) => try!(self.o_repeat(n)),
+
+                OpCode::AssignIndex => try!(self.o_assign_index()),
+                OpCode::GetIndex => try!(self.o_get_index()),
+
+                OpCode::Len => try!(self.o_len()),
+                OpCode::CONSTANT_INDEX(offset, from_end) => try!(self.o_constant_index(offset, from_end)),
+                OpCode::SUBSLICE(from, to) => try!(self.o_subslice(from, to)),
+                OpCode::SLICE_RANGE(kind) => try!(self.o_slice_range(kind)),
+                OpCode::VEC_SLICE => try!(self.o_vec_slice()),
+
+                OpCode::PTR_OFFSET => try!(self.o_ptr_offset()),
+                OpCode::PTR_COPY => try!(self.o_ptr_copy()),
+                OpCode::PTR_WRITE => try!(self.o_ptr_write()),
+                OpCode::UNINIT => {
+                    self.stack.push(StackData::Value(WrappedValue::None));
+                },
 
-                OpCode::SignedInteger(n) => {
-                    self.stack.push(StackData::Value(WrappedValue::I64(n)));
+                OpCode::DROP => try!(self.o_drop()),
+                OpCode::BOX => self.o_box(),
+                OpCode::MOVE => try!(self.o_move()),
+
+                OpCode::VEC_NEW => self.o_vec_new(),
+                OpCode::VEC_FROM_BOX => try!(self.o_vec_from_box()),
+                OpCode::VEC_PUSH => try!(self.o_vec_push()),
+                OpCode::VEC_POP => try!(self.o_vec_pop()),
+                OpCode::VEC_LEN => try!(self.o_vec_len()),
+                OpCode::VEC_INDEX => try!(self.o_vec_index()),
+                OpCode::VEC_ITER => try!(self.o_vec_iter()),
+                OpCode::ITER_NEXT => try!(self.o_iter_next()),
+
+                OpCode::Static(instance) => try!(self.o_static(instance)),
+                OpCode::StoreStatic(instance) => try!(self.o_store_static(instance)),
+                OpCode::LoadConst(instance) => try!(self.o_load_const(instance)),
+                OpCode::LoadPromoted(instance, index) => {
+                    try!(self.o_load_promoted(instance, index));
                 },
-                OpCode::UnsignedInteger(n) => {
-                    self.stack.push(StackData::Value(WrappedValue::U64(n)));
+
+                OpCode::SignedInteger(..) | OpCode::UnsignedInteger(..) | OpCode::Float(..) |
+                OpCode::Char(_) | OpCode::Str(_) => {
+                    self.stack.push(StackData::Value(WrappedValue::from_constant(opcode)));
                 },
                 OpCode::Usize(size) => {
                     self.stack.push(StackData::Value(WrappedValue::Usize(size)));
@@ -617,44 +1204,33 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                     self.stack.push(StackData::Value(WrappedValue::Bool(b)));
                 },
 
-                OpCode::StoreLocal(idx) => self.o_store_local(idx),
-                OpCode::LoadLocal(idx) => self.o_load_local(idx),
-                OpCode::BINOP(op) => self.o_binop(op),
-                OpCode::CBINOP(op) => self.o_cbinop(op),
+                OpCode::StoreLocal(idx) => try!(self.o_store_local(idx)),
+                OpCode::LoadLocal(idx) => try!(self.o_load_local(idx)),
+                OpCode::BINOP(op) => try!(self.o_binop(op)),
+                OpCode::UNOP(op) => try!(self.o_unop(op)),
+                OpCode::CAST(target) => try!(self.o_cast(target)),
+                OpCode::CBINOP(op) => try!(self.o_cbinop(op)),
 
-                OpCode::BORROW(..) => {
-                    let address = self.stack.pop().unwrap().unwrap_address();
-                    self.stack.push(StackData::Value(
-                        WrappedValue::Address(address)))
-                },
+                OpCode::ASSERT(expected, ref kind) => try!(self.o_assert(expected, kind)),
+                OpCode::PANIC => try!(self.o_panic()),
+                OpCode::PANIC_FMT => try!(self.o_panic_fmt()),
 
-                OpCode::DEREF => {
-                    let wrapped_target = self.pop_stack_value();
-                    if let WrappedValue::Address(target) = wrapped_target {
-                        match target {
-                            Address::StackLocal(_idx) => {
-                                self.stack.push(StackData::Pointer(target));
-                            },
-                            _ => unimplemented!()
-                        }
-                    }  else {
-                        panic!("can't resolve {:?}", wrapped_target);
-                    }
+                OpCode::Pop => {
+                    try!(self.pop());
                 },
 
+                OpCode::BORROW(..) => try!(self.o_borrow()),
+
+                OpCode::DEREF => try!(self.o_deref()),
+
                 OpCode::DEREF_STORE => {
-                    let wrapped_target = self.pop_stack_value();
-                    let value = self.pop_stack_value();
+                    let wrapped_target = try!(self.pop_stack_value()).thin();
+                    let value = try!(self.pop_stack_value());
 
                     if let WrappedValue::Address(target) = wrapped_target {
-                        match target {
-                            Address::StackLocal(idx) => {
-                                self.w_stack[idx] = value;
-                            }
-                            _ => unimplemented!()
-                        }
+                        try!(self.store(target, value));
                     } else {
-                        panic!("can't resolve {:?}", wrapped_target);
+                        return type_confusion("pointer", wrapped_target);
                     }
                 },
 
@@ -662,196 +1238,1151 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                     //XXX DO SOMETHING
                 },
 
-                _ => {
-                    println!("TODO {:?}", opcode);
-                    unimplemented!();
-                },
+                _ => return Err(ErrorKind::UnsupportedOpcode(format!("{:?}", opcode))),
             }
             pc += 1;
         }
 
         // println!("\nLocals: {:?}", self.w_stack);
+        Ok(())
     }
 
-    fn o_vec(&mut self, size: usize) {
-        let mut array: Vec<WrappedValue> = Vec::with_capacity(size);
+    fn o_vec(&mut self, size: usize) -> EvalResult<()> {
+        let mut array: Vec<WrappedValue<'a>> = Vec::with_capacity(size);
         for _ in 0..size {
             array.push(WrappedValue::None)
         }
 
         for idx in (0..size).rev() {
-            let val = self.pop_stack_value();
+            let val = try!(self.pop_stack_value());
             array[idx] = val;
         }
         self.stack.push(StackData::Value(WrappedValue::Array(array)));
+        Ok(())
     }
 
-    fn o_repeat(&mut self, size: usize) {
-        let mut array: Vec<WrappedValue> = Vec::with_capacity(size);
+    fn o_repeat(&mut self, size: usize) -> EvalResult<()> {
+        let mut array: Vec<WrappedValue<'a>> = Vec::with_capacity(size);
         for _ in 0..size {
             array.push(WrappedValue::None)
         }
 
-        let val = self.pop_stack_value();
+        let val = try!(self.pop_stack_value());
         for idx in 0..size {
             array[idx] = val.clone();
         }
 
         self.stack.push(StackData::Value(WrappedValue::Array(array)));
+        Ok(())
     }
 
-    fn o_len(&mut self) {
-        let v = self.pop_stack_value();
+    fn o_len(&mut self) -> EvalResult<()> {
+        let array = try!(self.pop());
+        let len = try!(self.length(&array));
+        self.stack.push(StackData::Value(WrappedValue::Usize(len)));
+        Ok(())
+    }
 
-        if let WrappedValue::Array(ref array) = v {
-            self.stack.push(StackData::Value(WrappedValue::Usize(array.len())));
-        } else {
-            panic!("expected array got {:?}", v);
+    // length of the array a Load points to, or of a slice or string
+    fn length(&mut self, array: &StackData<'a>) -> EvalResult<usize> {
+        match *array {
+            StackData::Value(WrappedValue::Slice(_, _, len)) => Ok(len),
+            StackData::Value(WrappedValue::Fat(_, Metadata::Len(len))) |
+            StackData::Value(WrappedValue::FatBox(_, Metadata::Len(len))) => Ok(len),
+            StackData::Value(WrappedValue::Str(ref s)) => Ok(s.len()),
+            _ => match try!(self.to_value(array)) {
+                WrappedValue::Array(ref array) => Ok(array.len()),
+                other => type_confusion("array", other),
+            },
         }
     }
 
-    fn o_get_index(&mut self) {
-        let array_address = self.stack.pop().unwrap().unwrap_address();
-        let index = self.pop_stack_value().unwrap_usize();
-
-        let object = match array_address {
-            Address::StackLocal(addr) => {
-                &self.w_stack[addr]
+    fn value_at(&mut self, address: &Address<'a>) -> EvalResult<&mut WrappedValue<'a>> {
+        match *address {
+            Address::StackLocal(idx) => Ok(&mut self.w_stack[idx]),
+            Address::Static(instance) => self.statics.get_mut(&instance).ok_or_else(|| {
+                ErrorKind::InvalidPointer(format!("{:?} isn't initialized", address))
+            }),
+            Address::Promoted(instance, index) => self.promoted.get_mut(&(instance, index)).ok_or_else(|| {
+                ErrorKind::InvalidPointer(format!("{:?} isn't initialized", address))
+            }),
+            Address::Heap(id) => self.heap_value(id),
+            Address::Path(ref root, ref steps) => {
+                let root = self.value_at(root);
+                steps.iter().fold(root, |value, &step| value.and_then(|value| value.step_mut(step)))
             },
-            Address::StackComplex(a, b) => {
-                &self.w_stack[a].unwrap_tuple().data[b]
+            Address::StaticFunc(_) => {
+                Err(ErrorKind::InvalidPointer(format!("{:?} can't be accessed", address)))
             },
-            _ => unimplemented!(),
-        };
+        }
+    }
 
-        if let WrappedValue::Array(ref array) = *object {
-            //XXX clone
-            let val = array[index].clone();
-            self.stack.push(StackData::Value(val));
+    fn o_get_index(&mut self) -> EvalResult<()> {
+        let array = try!(self.pop());
+        let index = try!(self.pop_usize());
+
+        // indexing a string reads its bytes
+        if let StackData::Value(WrappedValue::Str(ref s)) = array {
+            let byte = match s.as_bytes().get(index) {
+                Some(&byte) => byte,
+                None => return Err(ErrorKind::OutOfBounds(index as isize, s.len())),
+            };
+            self.stack.push(StackData::Value(WrappedValue::U8(byte)));
+            return Ok(());
         }
+
+        let address = try!(element_address(array, index));
+        self.stack.push(StackData::Pointer(address));
+        Ok(())
     }
 
-    fn o_assign_index(&mut self) {
-        let array_address = self.stack.pop().unwrap().unwrap_address();
-        let index = self.pop_stack_value().unwrap_usize();
-        let value = self.pop_stack_value();
+    fn o_assign_index(&mut self) -> EvalResult<()> {
+        let array = try!(self.pop());
+        let index = try!(self.pop_usize());
+        let value = try!(self.pop_stack_value());
 
-        let mut obj = match array_address {
-            Address::StackLocal(addr) => {
-                &mut self.w_stack[addr]
-            },
-            Address::StackComplex(a, b) => {
-                &mut self.w_stack[a].unwrap_tuple().data[b]
-            }
-            _ => unimplemented!(),
-        };
+        let address = try!(element_address(array, index));
+        self.store(address, value)
+    }
 
-        if let WrappedValue::Array(ref mut array) = *obj {
-            array[index] = value;
+    fn o_constant_index(&mut self, offset: usize, from_end: bool) -> EvalResult<()> {
+        let array = try!(self.pop());
+        let index = if from_end {
+            let len = try!(self.length(&array));
+            match len.checked_sub(offset) {
+                Some(index) => index,
+                None => return Err(ErrorKind::OutOfBounds(len as isize - offset as isize, len)),
+            }
         } else {
-            unimplemented!()
-        }
+            offset
+        };
 
+        self.stack.push(StackData::Value(WrappedValue::Usize(index)));
+        self.stack.push(array);
+        Ok(())
     }
 
-    fn o_tuple(&mut self, size: usize) {
-        self.stack.push(StackData::Value(WrappedValue::Tuple(WrappedTuple::with_size(size))));
-    }
+    fn o_subslice(&mut self, from: usize, to: usize) -> EvalResult<()> {
+        let array = try!(self.pop());
+        let len = try!(self.length(&array));
+        let sub_len = match len.checked_sub(from).and_then(|rest| rest.checked_sub(to)) {
+            Some(sub_len) => sub_len,
+            None => return Err(ErrorKind::OutOfBounds((from + to) as isize, len)),
+        };
 
-    fn o_tuple_set(&mut self, idx: usize) {
-        let tuple_address = self.stack.pop().unwrap().unwrap_address();
-        let value = self.pop_stack_value();
+        let slice = match array {
+            StackData::Pointer(address) => WrappedValue::Slice(address, from, sub_len),
+            StackData::Value(WrappedValue::Slice(base, start, _)) => {
+                WrappedValue::Slice(base, start + from, sub_len)
+            },
+            other => return type_confusion("array or slice", other),
+        };
+        self.stack.push(StackData::Value(slice));
+        Ok(())
+    }
 
-        match tuple_address {
-            Address::StackLocal(addr) => {
-                if let WrappedValue::Tuple(ref mut tuple) = self.w_stack[addr] {
-                    tuple.data[idx] = value;
+    // the slices of a Vec and of a boxed array start at the beginning
+    fn pop_slice(&mut self) -> EvalResult<(Address<'a>, usize, usize)> {
+        match try!(self.pop_stack_value()) {
+            WrappedValue::Slice(base, start, len) => Ok((base, start, len)),
+            WrappedValue::Fat(address, Metadata::Len(len)) => Ok((address, 0, len)),
+            WrappedValue::FatBox(id, Metadata::Len(len)) => Ok((Address::Heap(id), 0, len)),
+            WrappedValue::Address(address) => {
+                match try!(self.to_value(&StackData::Pointer(address))) {
+                    WrappedValue::Box(id) => {
+                        let len = try!(self.heap_array(id)).len();
+                        Ok((Address::Heap(id), 0, len))
+                    },
+                    other => type_confusion("Vec", other),
                 }
             },
-            _ => panic!("can not load tuple at {:?}", tuple_address),
+            other => type_confusion("slice", other),
         }
     }
 
-    fn o_tuple_assign(&mut self, idx: usize) {
-        let value = self.pop_stack_value();
-        let mut s_tuple = self.stack.last_mut().unwrap();
+    fn o_vec_slice(&mut self) -> EvalResult<()> {
+        let (base, start, len) = try!(self.pop_slice());
+        self.stack.push(StackData::Value(WrappedValue::Slice(base, start, len)));
+        Ok(())
+    }
 
-        if let StackData::Value(WrappedValue::Tuple(ref mut tuple)) = *s_tuple  {
-            tuple.data[idx] = value;
-        } else {
-            panic!("Expected tuple found {:?}", s_tuple);
+    // `Index::index` with a range, which is checked like in libcore
+    fn o_slice_range(&mut self, kind: RangeKind) -> EvalResult<()> {
+        let mut range = try!(self.pop_stack_value());
+        let (base, start, len) = try!(self.pop_slice());
+
+        let (from, to) = {
+            let fields = try!(range.unwrap_tuple());
+            match kind {
+                RangeKind::Range => {
+                    (try!(try!(fields.field(0)).unwrap_usize()), try!(try!(fields.field(1)).unwrap_usize()))
+                },
+                RangeKind::RangeFrom => (try!(try!(fields.field(0)).unwrap_usize()), len),
+                RangeKind::RangeTo => (0, try!(try!(fields.field(0)).unwrap_usize())),
+                RangeKind::RangeFull => (0, len),
+            }
+        };
+
+        if from > to {
+            self.start_panic(format!("slice index starts at {} but ends at {}", from, to));
+        } else if to > len {
+            self.start_panic(format!("index {} out of range for slice of length {}", to, len));
         }
+
+        let slice = if self.panic.is_some() {
+            WrappedValue::Slice(base, start, 0)
+        } else {
+            WrappedValue::Slice(base, start + from, to - from)
+        };
+        self.stack.push(StackData::Value(slice));
+        Ok(())
     }
 
-    fn o_tuple_get(&mut self, idx: usize) {
-        // let s_tuple = self.pop_stack_value();
-        match self.stack.pop().unwrap().unwrap_address() {
-            Address::StackLocal(tuple_address) => {
-                self.stack.push(StackData::Pointer(
-                    Address::StackComplex(tuple_address, idx)));
-            },
-            _ => unimplemented!(),
+    fn pop_pointer(&mut self) -> EvalResult<Address<'a>> {
+        match try!(self.pop_stack_value()) {
+            WrappedValue::Address(address) => Ok(address),
+            other => type_confusion("pointer", other),
         }
     }
 
-    fn o_store_local(&mut self, idx: usize) {
-        let v = self.stack.pop().unwrap();
-        //XXXX
-        let val = match v {
-            StackData::Value(v) => v,
-            StackData::Pointer(Address::StackLocal(other)) => {
-                self.w_stack[other].clone()
-            },
-            StackData::Pointer(Address::StaticFunc(defid)) => {
-                WrappedValue::Address(Address::StaticFunc(defid))
+    // Only pointers to array elements can be moved, up to one past the end
+    // of the array.
+    fn offset_address(&mut self, address: Address<'a>, count: isize) -> EvalResult<Address<'a>> {
+        if count == 0 {
+            return Ok(address);
+        }
+
+        match address {
+            Address::Path(root, mut steps) => {
+                let index = steps.pop().unwrap() as isize + count;
+                let array = if steps.is_empty() {
+                    (*root).clone()
+                } else {
+                    Address::Path(root.clone(), steps.clone())
+                };
+
+                let len = match *try!(self.value_at(&array)) {
+                    WrappedValue::Array(ref array) => array.len(),
+                    ref other => return type_confusion("array", other),
+                };
+                if index < 0 || index as usize > len {
+                    return Err(ErrorKind::OutOfBounds(index, len));
+                }
+
+                steps.push(index as usize);
+                Ok(Address::Path(root, steps))
             },
-            StackData::Pointer(Address::StackComplex(a, b)) => {
-                let tuple = self.w_stack[a].unwrap_tuple();
-                tuple.data[b].clone()
+            other => {
+                let message = format!("{:?} doesn't point into an array", other);
+                Err(ErrorKind::InvalidPointer(message))
             },
+        }
+    }
 
-            _ => panic!("should not store interpreter level object {:?}", v)
+    fn o_ptr_offset(&mut self) -> EvalResult<()> {
+        let count = match try!(self.pop_stack_value()) {
+            WrappedValue::Isize(count) => count,
+            other => return type_confusion("Isize", other),
         };
+        let address = try!(self.pop_pointer());
 
-        self.w_stack[self.w_stack_pointer + idx] = val;
+        let address = try!(self.offset_address(address, count));
+        self.stack.push(StackData::Value(WrappedValue::Address(address)));
+        Ok(())
     }
 
-    fn o_load_local(&mut self, idx: usize) {
-        self.stack.push(StackData::Pointer(Address::StackLocal(self.w_stack_pointer + idx)))
-    }
+    // `copy` allows overlapping, so all values are read before writing
+    fn o_ptr_copy(&mut self) -> EvalResult<()> {
+        let count = try!(self.pop_usize());
+        let dst = try!(self.pop_pointer());
+        let src = try!(self.pop_pointer());
 
-    fn o_cbinop(&mut self, op: BinOp) {
-        let mut tuple = WrappedTuple::with_size(2);
-        self.o_binop(op);
-        tuple.data[0] = self.pop_stack_value();
-        // false == no error
-        tuple.data[1] = WrappedValue::Bool(false);
-        self.stack.push(StackData::Value(WrappedValue::Tuple(tuple)));
+        let mut values = Vec::with_capacity(count);
+        for i in 0..count {
+            let address = try!(self.offset_address(src.clone(), i as isize));
+            values.push(try!(self.value_at(&address)).clone());
+        }
+        for (i, value) in values.into_iter().enumerate() {
+            let address = try!(self.offset_address(dst.clone(), i as isize));
+            try!(self.store(address, value));
+        }
+        self.stack.push(StackData::Value(WrappedValue::Tuple(WrappedTuple::with_size(0))));
+        Ok(())
     }
 
-    fn o_binop(&mut self, op: BinOp) {
-        use self::WrappedValue::*;
-        use rustc::mir::repr::BinOp::*;
+    // `move_val_init`, which stores without dropping the old value
+    fn o_ptr_write(&mut self) -> EvalResult<()> {
+        let value = try!(self.pop_stack_value());
+        let dst = try!(self.pop_pointer());
 
-        let right = self.pop_stack_value();
-        let left = self.pop_stack_value();
+        try!(self.store(dst, value));
+        self.stack.push(StackData::Value(WrappedValue::Tuple(WrappedTuple::with_size(0))));
+        Ok(())
+    }
 
-        // copied from miri
-        macro_rules! int_binops {
+    // slices and strings are references already
+    // Dereferencing a trait object keeps its vtable, rustc calls a method
+    // on `obj` with `&*obj` as the receiver.
+    fn o_deref(&mut self) -> EvalResult<()> {
+        let target = match try!(self.pop_stack_value()) {
+            WrappedValue::Fat(address, metadata @ Metadata::Vtable(_)) => {
+                StackData::Value(WrappedValue::Fat(address, metadata))
+            },
+            WrappedValue::FatBox(id, metadata @ Metadata::Vtable(_)) => {
+                StackData::Value(WrappedValue::Fat(Address::Heap(id), metadata))
+            },
+            value => match value.thin() {
+                WrappedValue::Address(address) => StackData::Pointer(address),
+                value @ WrappedValue::Str(_) |
+                value @ WrappedValue::Slice(..) => StackData::Value(value),
+                other => return type_confusion("pointer", other),
+            },
+        };
+        self.stack.push(target);
+        Ok(())
+    }
+
+    fn o_borrow(&mut self) -> EvalResult<()> {
+        let reference = match try!(self.pop()) {
+            StackData::Pointer(address) => WrappedValue::Address(address),
+            StackData::Value(value @ WrappedValue::Slice(..)) |
+            StackData::Value(value @ WrappedValue::Str(_)) |
+            StackData::Value(value @ WrappedValue::Fat(..)) => value,
+            other => return type_confusion("Pointer, slice, str or trait object", other),
+        };
+        self.stack.push(StackData::Value(reference));
+        Ok(())
+    }
+
+    fn o_box(&mut self) {
+        let id = self.allocate(WrappedValue::None);
+        self.stack.push(StackData::Value(WrappedValue::Box(id)));
+    }
+
+    fn allocate(&mut self, value: WrappedValue<'a>) -> usize {
+        let id = self.next_allocation;
+        self.next_allocation += 1;
+        self.heap.insert(id, value);
+        id
+    }
+
+    fn free(&mut self, id: usize) -> EvalResult<WrappedValue<'a>> {
+        self.heap.remove(&id).ok_or_else(|| {
+            ErrorKind::InvalidPointer(format!("double free of heap allocation {}", id))
+        })
+    }
+
+    fn heap_value(&mut self, id: usize) -> EvalResult<&mut WrappedValue<'a>> {
+        self.heap.get_mut(&id).ok_or_else(|| {
+            ErrorKind::InvalidPointer(format!("use after free of heap allocation {}", id))
+        })
+    }
+
+    fn heap_array(&mut self, id: usize) -> EvalResult<&mut Vec<WrappedValue<'a>>> {
+        match *try!(self.heap_value(id)) {
+            WrappedValue::Array(ref mut array) => Ok(array),
+            ref other => type_confusion("array", other),
+        }
+    }
+
+    fn store(&mut self, address: Address<'a>, value: WrappedValue<'a>) -> EvalResult<()> {
+        match address {
+            // a static is stored before it's initialized
+            Address::Static(instance) => {
+                self.statics.insert(instance, value);
+            },
+            _ => {
+                *try!(self.value_at(&address)) = value;
+            },
+        }
+        Ok(())
+    }
+
+    // A Vec is a box of an array. The methods get a reference to the Vec.
+    fn pop_vec(&mut self) -> EvalResult<usize> {
+        let reference = try!(self.pop_stack_value()).thin();
+        match reference {
+            WrappedValue::Address(address) => {
+                match try!(self.to_value(&StackData::Pointer(address))) {
+                    WrappedValue::Box(id) => Ok(id),
+                    other => type_confusion("Vec", other),
+                }
+            },
+            other => type_confusion("reference to Vec", other),
+        }
+    }
+
+    fn o_vec_new(&mut self) {
+        let id = self.allocate(WrappedValue::Array(Vec::new()));
+        self.stack.push(StackData::Value(WrappedValue::Box(id)));
+    }
+
+    // Box<[T]> -> Vec<T>, the boxed array is reused
+    fn o_vec_from_box(&mut self) -> EvalResult<()> {
+        match try!(self.pop_stack_value()) {
+            WrappedValue::FatBox(id, _) => {
+                self.stack.push(StackData::Value(WrappedValue::Box(id)));
+            },
+            other => return type_confusion("boxed slice", other),
+        }
+        Ok(())
+    }
+
+    fn o_vec_push(&mut self) -> EvalResult<()> {
+        let value = try!(self.pop_stack_value());
+        let id = try!(self.pop_vec());
+        try!(self.heap_array(id)).push(value);
+        self.stack.push(StackData::Value(WrappedValue::Tuple(WrappedTuple::with_size(0))));
+        Ok(())
+    }
+
+    fn o_vec_pop(&mut self) -> EvalResult<()> {
+        let id = try!(self.pop_vec());
+        let value = try!(self.heap_array(id)).pop();
+        self.stack.push(StackData::Value(option(value)));
+        Ok(())
+    }
+
+    fn o_vec_len(&mut self) -> EvalResult<()> {
+        let id = try!(self.pop_vec());
+        let len = try!(self.heap_array(id)).len();
+        self.stack.push(StackData::Value(WrappedValue::Usize(len)));
+        Ok(())
+    }
+
+    // `Index::index` returns a reference to the element
+    fn o_vec_index(&mut self) -> EvalResult<()> {
+        let index = try!(self.pop_usize());
+        let id = try!(self.pop_vec());
+
+        let len = try!(self.heap_array(id)).len();
+        if index >= len {
+            self.start_panic(format!("index out of bounds: the len is {} but the index is {}",
+                                     len, index));
+        }
+        self.stack.push(StackData::Value(WrappedValue::Address(Address::Heap(id).project(index))));
+        Ok(())
+    }
+
+    // An iterator is a tuple of the Vec and the position of the next
+    // element. Iterating over a Vec by value moves the elements out, for a
+    // reference the iterator only borrows the array.
+    fn o_vec_iter(&mut self) -> EvalResult<()> {
+        let vec = match try!(self.pop_stack_value()) {
+            WrappedValue::Box(id) => WrappedValue::Box(id),
+            WrappedValue::Address(address) => {
+                match try!(self.to_value(&StackData::Pointer(address))) {
+                    WrappedValue::Box(id) => WrappedValue::Address(Address::Heap(id)),
+                    other => return type_confusion("Vec", other),
+                }
+            },
+            other => return type_confusion("Vec", other),
+        };
+
+        let mut iter = WrappedTuple::with_size(2);
+        iter.data[0] = vec;
+        iter.data[1] = WrappedValue::Usize(0);
+        self.stack.push(StackData::Value(WrappedValue::Tuple(iter)));
+        Ok(())
+    }
+
+    fn o_iter_next(&mut self) -> EvalResult<()> {
+        let address = match try!(self.pop_stack_value()) {
+            WrappedValue::Address(address) => address,
+            other => return type_confusion("reference to iterator", other),
+        };
+
+        let mut iter = try!(self.to_value(&StackData::Pointer(address.clone())));
+        let (id, by_value, position) = {
+            let tuple = try!(iter.unwrap_tuple());
+            let (id, by_value) = match *try!(tuple.field(0)) {
+                WrappedValue::Box(id) => (id, true),
+                WrappedValue::Address(Address::Heap(id)) => (id, false),
+                ref other => return type_confusion("Vec", other),
+            };
+            (id, by_value, try!(try!(tuple.field(1)).unwrap_usize()))
+        };
+
+        let item = if position < try!(self.heap_array(id)).len() {
+            *try!(try!(iter.unwrap_tuple()).field(1)) = WrappedValue::Usize(position + 1);
+            if by_value {
+                Some(mem::replace(&mut try!(self.heap_array(id))[position], WrappedValue::None))
+            } else {
+                Some(WrappedValue::Address(Address::Heap(id).project(position)))
+            }
+        } else {
+            None
+        };
+
+        try!(self.store(address, iter));
+        self.stack.push(StackData::Value(option(item)));
+        Ok(())
+    }
+
+    fn o_move(&mut self) -> EvalResult<()> {
+        let data = try!(self.pop());
+        let value = try!(self.to_value(&data));
+
+        // like o_drop, the location doesn't own the value anymore
+        match data {
+            StackData::Pointer(Address::Static(_)) |
+            StackData::Pointer(Address::Promoted(..)) |
+            StackData::Pointer(Address::StaticFunc(_)) => {},
+            StackData::Pointer(address) => {
+                try!(self.store(address, WrappedValue::None));
+            },
+            _ => {},
+        }
+
+        self.stack.push(StackData::Value(value));
+        Ok(())
+    }
+
+    fn o_tuple(&mut self, size: usize) {
+        self.stack.push(StackData::Value(WrappedValue::Tuple(WrappedTuple::with_size(size))));
+    }
+
+    fn o_variant(&mut self, variant: usize, size: usize) {
+        let enm = WrappedEnum { variant: variant, data: WrappedTuple::with_size(size) };
+        self.stack.push(StackData::Value(WrappedValue::Enum(enm)));
+    }
+
+    fn o_destructor(&mut self, instance: Instance<'a>) -> EvalResult<()> {
+        match self.stack.last_mut() {
+            Some(&mut StackData::Value(ref mut aggregate)) => {
+                try!(aggregate.unwrap_tuple()).destructor = Some(instance);
+            },
+            Some(other) => return type_confusion("aggregate", other),
+            None => return Err(ErrorKind::StackUnderflow),
+        }
+        Ok(())
+    }
+
+    fn o_drop(&mut self) -> EvalResult<()> {
+        let data = try!(self.pop());
+        let value = try!(self.to_value(&data));
+
+        // mark the location as moved out, statics are never dropped
+        match data {
+            StackData::Pointer(Address::Static(_)) |
+            StackData::Pointer(Address::Promoted(..)) |
+            StackData::Pointer(Address::StaticFunc(_)) => {},
+            StackData::Pointer(address) => {
+                try!(self.store(address, WrappedValue::None));
+            },
+            _ => {},
+        }
+
+        self.drop_value(value)
+    }
+
+    fn drop_value(&mut self, value: WrappedValue<'a>) -> EvalResult<()> {
+        match value {
+            WrappedValue::Box(id) => {
+                let contents = try!(self.free(id));
+                return self.drop_value(contents);
+            },
+            // only the vtable knows the destructor of the concrete type
+            WrappedValue::FatBox(id, Metadata::Vtable(ref vtable)) => {
+                let mut contents = try!(self.free(id));
+                if let Ok(aggregate) = contents.unwrap_tuple() {
+                    aggregate.destructor = vtable.drop;
+                }
+                return self.drop_value(contents);
+            },
+            WrappedValue::FatBox(id, Metadata::Len(_)) => {
+                let contents = try!(self.free(id));
+                return self.drop_value(contents);
+            },
+            _ => {},
+        }
+
+        let destructor = match value {
+            WrappedValue::Tuple(ref tuple) => tuple.destructor,
+            WrappedValue::Enum(ref enm) => enm.data.destructor,
+            _ => None,
+        };
+
+        // a user defined `Drop::drop` runs before the fields get dropped
+        let value = match destructor {
+            Some(instance) => try!(self.call_destructor(instance, value)),
+            None => value,
+        };
+
+        let fields = match value {
+            WrappedValue::Tuple(tuple) => tuple.data,
+            WrappedValue::Enum(enm) => enm.data.data,
+            WrappedValue::Array(array) => array,
+            _ => return Ok(()),
+        };
+
+        for field in fields {
+            try!(self.drop_value(field));
+        }
+        Ok(())
+    }
+
+    // The value is parked in the slot right behind the current frame. The
+    // destructor gets a reference to it and its own frame is placed after it.
+    fn call_destructor(&mut self, instance: Instance<'a>, value: WrappedValue<'a>) -> EvalResult<WrappedValue<'a>> {
+        let slot = self.frame_end();
+        for _ in self.w_stack.len() .. slot + 1 {
+            self.w_stack.push(WrappedValue::None);
+        }
+        self.w_stack[slot] = value;
+
+        let height = self.stack.len();
+        self.stack.push(StackData::Value(WrappedValue::Address(Address::StackLocal(slot))));
+        self.stack.push(StackData::ArgCount(1));
+
+        // drop glue is recorded as a single DROP, don't trace the destructor
+        let is_tracing = self.is_tracing;
+        self.is_tracing = false;
+
+        // cleanup blocks drop values while a panic is in flight, the
+        // destructor itself has to run normally
+        let unwinding = self.panic.take();
+
+        let func = self.program.get_func(instance);
+        try!(self.eval_func(instance.def_id, func, slot + 1));
+        self.is_tracing = is_tracing;
+
+        if let Some(panic) = unwinding {
+            if let Some(ref nested) = self.panic {
+                return Err(ErrorKind::DoublePanic(nested.message.clone(), panic.message));
+            }
+            self.panic = Some(panic);
+        }
+
+        // discard the return value
+        self.stack.truncate(height);
+
+        Ok(mem::replace(&mut self.w_stack[slot], WrappedValue::None))
+    }
+
+    fn o_static(&mut self, instance: Instance<'a>) -> EvalResult<()> {
+        try!(self.init_static(instance));
+        self.stack.push(StackData::Pointer(Address::Static(instance)));
+        Ok(())
+    }
+
+    fn o_store_static(&mut self, instance: Instance<'a>) -> EvalResult<()> {
+        // initialize first, so the write isn't overwritten later on
+        try!(self.init_static(instance));
+        let value = try!(self.pop_stack_value());
+        self.statics.insert(instance, value);
+        Ok(())
+    }
+
+    fn o_load_const(&mut self, instance: Instance<'a>) -> EvalResult<()> {
+        try!(self.init_static(instance));
+        let value = self.statics[&instance].clone();
+        self.stack.push(StackData::Value(value));
+        Ok(())
+    }
+
+    // Statics and constants are evaluated the first time they are used.
+    fn init_static(&mut self, instance: Instance<'a>) -> EvalResult<()> {
+        if self.statics.contains_key(&instance) {
+            return Ok(());
+        }
+
+        let base = self.frame_end();
+        let func = self.program.get_func(instance);
+        let mut value = try!(self.eval_initializer(instance.def_id, func));
+        self.persist_temporaries(&mut value, base, &mut HashMap::new());
+        self.statics.insert(instance, value);
+        Ok(())
+    }
+
+    // A static like `&[1, 2, 3]` refers to temporaries of its initializer,
+    // which lie behind the current frame and are overwritten by the next
+    // call. They are moved to the heap, where a static keeps them alive.
+    fn persist_temporaries(&mut self, value: &mut WrappedValue<'a>, base: usize,
+                           moved: &mut HashMap<usize, usize>) {
+        match *value {
+            WrappedValue::Address(ref mut address) |
+            WrappedValue::Fat(ref mut address, _) |
+            WrappedValue::Slice(ref mut address, _, _) => {
+                let root = match *address {
+                    Address::Path(ref mut root, _) => &mut **root,
+                    ref mut root => root,
+                };
+                if let Address::StackLocal(idx) = *root {
+                    if idx >= base {
+                        let id = match moved.get(&idx) {
+                            Some(&id) => id,
+                            None => {
+                                let mut temporary = mem::replace(&mut self.w_stack[idx],
+                                                                 WrappedValue::None);
+                                let id = self.allocate(WrappedValue::None);
+                                moved.insert(idx, id);
+                                self.persist_temporaries(&mut temporary, base, moved);
+                                self.heap.insert(id, temporary);
+                                id
+                            },
+                        };
+                        *root = Address::Heap(id);
+                    }
+                }
+            },
+            WrappedValue::Tuple(ref mut tuple) |
+            WrappedValue::Enum(WrappedEnum { data: ref mut tuple, .. }) => {
+                for field in &mut tuple.data {
+                    self.persist_temporaries(field, base, moved);
+                }
+            },
+            WrappedValue::Array(ref mut items) => {
+                for item in items {
+                    self.persist_temporaries(item, base, moved);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // A promoted constant is evaluated once. Its body returns a reference to
+    // one of its temporaries, the value is kept alive in the promoted table.
+    fn o_load_promoted(&mut self, instance: Instance<'a>, index: usize) -> EvalResult<()> {
+        let key = (instance, index);
+        if !self.promoted.contains_key(&key) {
+            let base = self.frame_end();
+            let func = self.program.get_promoted(instance, index);
+
+            let value = match try!(self.eval_initializer(instance.def_id, func)) {
+                WrappedValue::Address(Address::StackLocal(idx)) if idx >= base => {
+                    mem::replace(&mut self.w_stack[idx], WrappedValue::None)
+                },
+                other => return type_confusion("reference to a temporary", other),
+            };
+            self.promoted.insert(key, value);
+        }
+
+        self.stack.push(StackData::Value(WrappedValue::Address(Address::Promoted(instance, index))));
+        Ok(())
+    }
+
+    // Runs the MIR of an initializer in a frame behind the current one and
+    // returns the result.
+    fn eval_initializer(&mut self, def_id: DefId, func: Rc<Function<'a>>) -> EvalResult<WrappedValue<'a>> {
+        let height = self.stack.len();
+        self.stack.push(StackData::ArgCount(0));
+
+        // the initializer runs only once, it doesn't belong into a trace
+        let is_tracing = self.is_tracing;
+        self.is_tracing = false;
+
+        let base = self.frame_end();
+        try!(self.eval_func(def_id, func, base));
+        self.is_tracing = is_tracing;
+
+        let value = try!(self.pop_stack_value());
+        if self.stack.len() != height {
+            return Err(ErrorKind::UnbalancedStack(height, self.stack.len()));
+        }
+        Ok(value)
+    }
+
+    // position of the receiver of the call which is being prepared
+    fn receiver_position(&self) -> EvalResult<usize> {
+        match self.stack.last() {
+            Some(&StackData::ArgCount(n)) => Ok(self.stack.len() - 1 - n),
+            other => type_confusion("ArgCount", other),
+        }
+    }
+
+    fn virtual_callee(&mut self, idx: usize) -> EvalResult<Instance<'a>> {
+        let data = self.stack[try!(self.receiver_position())].clone();
+        match try!(self.to_value(&data)) {
+            WrappedValue::Fat(_, Metadata::Vtable(ref vtable)) |
+            WrappedValue::FatBox(_, Metadata::Vtable(ref vtable)) => {
+                match vtable.methods.get(idx) {
+                    Some(&Some(instance)) => Ok(instance),
+                    _ => {
+                        let call = format!("VIRTUAL({}) of a method without a vtable entry", idx);
+                        Err(ErrorKind::UnsupportedOpcode(call))
+                    },
+                }
+            },
+            other => type_confusion("trait object", other),
+        }
+    }
+
+    fn callee(&mut self, data: &StackData<'a>) -> EvalResult<Instance<'a>> {
+        match try!(self.to_value(data)) {
+            WrappedValue::Address(Address::StaticFunc(instance)) => Ok(instance),
+            other => type_confusion("function", other),
+        }
+    }
+
+    fn o_virtual(&mut self, idx: usize) -> EvalResult<()> {
+        let instance = try!(self.virtual_callee(idx));
+
+        // the method of the impl takes a thin pointer to self
+        let position = try!(self.receiver_position());
+        let data = self.stack[position].clone();
+        let receiver = try!(self.to_value(&data)).thin();
+        self.stack[position] = StackData::Value(receiver);
+
+        self.stack.push(StackData::Pointer(Address::StaticFunc(instance)));
+        Ok(())
+    }
+
+    fn o_unsize(&mut self, metadata: &Metadata<'a>) -> EvalResult<()> {
+        match try!(self.pop_stack_value()) {
+            WrappedValue::Address(address) => {
+                if let Metadata::Len(len) = *metadata {
+                    self.stack.push(StackData::Value(WrappedValue::Slice(address, 0, len)));
+                    return Ok(());
+                }
+                self.stack.push(StackData::Value(WrappedValue::Fat(address, metadata.clone())));
+            },
+            // the box still owns the allocation
+            WrappedValue::Box(id) => {
+                self.stack.push(StackData::Value(WrappedValue::FatBox(id, metadata.clone())));
+            },
+            other => return type_confusion("pointer or Box", other),
+        }
+        Ok(())
+    }
+
+    fn o_untuple(&mut self, size: usize) -> EvalResult<()> {
+        let mut value = try!(self.pop_stack_value());
+        let tuple = try!(value.unwrap_tuple());
+        if tuple.data.len() != size {
+            return type_confusion(&format!("tuple of {} fields", size), &tuple.data);
+        }
+
+        for field in tuple.data.drain(..) {
+            self.stack.push(StackData::Value(field));
+        }
+        Ok(())
+    }
+
+    fn o_tuple_set(&mut self, idx: usize) -> EvalResult<()> {
+        let tuple_address = try!(try!(self.pop()).unwrap_address());
+        let value = try!(self.pop_stack_value());
+
+        self.store(tuple_address.project(idx), value)
+    }
+
+    fn o_tuple_assign(&mut self, idx: usize) -> EvalResult<()> {
+        let value = try!(self.pop_stack_value());
+
+        match self.stack.last_mut() {
+            Some(&mut StackData::Value(ref mut aggregate)) => {
+                *try!(try!(aggregate.unwrap_tuple()).field(idx)) = value;
+            },
+            Some(other) => return type_confusion("tuple", other),
+            None => return Err(ErrorKind::StackUnderflow),
+        }
+        Ok(())
+    }
+
+    fn o_tuple_get(&mut self, idx: usize) -> EvalResult<()> {
+        let tuple_address = try!(try!(self.pop()).unwrap_address());
+        self.stack.push(StackData::Pointer(tuple_address.project(idx)));
+        Ok(())
+    }
+
+    fn o_store_local(&mut self, idx: usize) -> EvalResult<()> {
+        let v = try!(self.pop());
+        let val = try!(self.to_value(&v));
+
+        let slot = try!(self.frame()).base + idx;
+        self.w_stack[slot] = val;
+        Ok(())
+    }
+
+    fn o_load_local(&mut self, idx: usize) -> EvalResult<()> {
+        let slot = try!(self.frame()).base + idx;
+        self.stack.push(StackData::Pointer(Address::StackLocal(slot)));
+        Ok(())
+    }
+
+    fn o_assert(&mut self, expected: bool, kind: &AssertKind) -> EvalResult<()> {
+        let cond = try!(try!(self.pop_stack_value()).unwrap_bool());
+
+        let message = match *kind {
+            AssertKind::BoundsCheck => {
+                let index = try!(self.pop_usize());
+                let len = try!(self.pop_usize());
+                format!("index out of bounds: the len is {} but the index is {}", len, index)
+            },
+            AssertKind::Math(description) => description.to_string(),
+        };
+
+        if cond != expected {
+            self.start_panic(message);
+        }
+        Ok(())
+    }
+
+    fn o_panic(&mut self) -> EvalResult<()> {
+        match try!(self.pop_stack_value()) {
+            WrappedValue::Str(message) => self.start_panic(message.to_string()),
+            other => return type_confusion("Str", other),
+        }
+        Ok(())
+    }
+
+    // The arguments of the message would need the guest's `Display` impls,
+    // only the literal pieces of the format string are kept.
+    fn o_panic_fmt(&mut self) -> EvalResult<()> {
+        let arguments = match try!(self.pop_stack_value()) {
+            WrappedValue::Address(address) => try!(self.value_at(&address)).clone(),
+            other => other,
+        };
+        // fmt::Arguments { pieces, fmt, args }
+        let pieces = match arguments {
+            WrappedValue::Tuple(ref tuple) if !tuple.data.is_empty() => tuple.data[0].clone(),
+            other => return type_confusion("fmt::Arguments", other),
+        };
+        let (array, start, len) = match pieces {
+            WrappedValue::Slice(array, start, len) => (array, start, len),
+            other => return type_confusion("slice of pieces", other),
+        };
+
+        let mut message = String::new();
+        for i in start..start + len {
+            match *try!(self.value_at(&array.clone().project(i))) {
+                WrappedValue::Str(ref piece) => message.push_str(piece),
+                ref other => return type_confusion("Str", other),
+            }
+        }
+        self.start_panic(message);
+        Ok(())
+    }
+
+    fn start_panic(&mut self, message: String) {
+        // the failing path is not worth tracing
+        if self.is_tracing {
+            self.is_tracing = false;
+            self.active_trace = Trace::new();
+        }
+
+        self.panic = Some(Panic { message: message, backtrace: Vec::new() });
+    }
+
+    // leave the current function while unwinding
+    fn o_unwind_frame(&mut self) -> EvalResult<()> {
+        let def_id = try!(self.frame()).def_id;
+        match self.panic {
+            Some(ref mut panic) => panic.backtrace.push(def_id),
+            None => return Err(ErrorKind::NotUnwinding),
+        }
+        self.frames.pop();
+        Ok(())
+    }
+
+    fn o_cbinop(&mut self, op: BinOp) -> EvalResult<()> {
+        use self::WrappedValue::*;
+        use rustc::mir::repr::BinOp::*;
+
+        let right = try!(self.pop_stack_value());
+        let left = try!(self.pop_stack_value());
+
+        macro_rules! checked_binops {
+            ($v:ident, $l:ident, $r:ident) => ({
+                let (val, overflow) = match op {
+                    Add => $l.overflowing_add($r),
+                    Sub => $l.overflowing_sub($r),
+                    Mul => $l.overflowing_mul($r),
+                    _ => return Err(ErrorKind::UnsupportedOpcode(format!("CBINOP({:?})", op))),
+                };
+                ($v(val), overflow)
+            })
+        }
+
+        let (value, overflow) = if op == Shl || op == Shr {
+            try!(shift(op, left, try!(right.shift_amount())))
+        } else {
+            match (left, right) {
+                (I8(l), I8(r)) => checked_binops!(I8, l, r),
+                (I16(l), I16(r)) => checked_binops!(I16, l, r),
+                (I32(l), I32(r)) => checked_binops!(I32, l, r),
+                (I64(l), I64(r)) => checked_binops!(I64, l, r),
+                (Isize(l), Isize(r)) => checked_binops!(Isize, l, r),
+                (U8(l), U8(r)) => checked_binops!(U8, l, r),
+                (U16(l), U16(r)) => checked_binops!(U16, l, r),
+                (U32(l), U32(r)) => checked_binops!(U32, l, r),
+                (U64(l), U64(r)) => checked_binops!(U64, l, r),
+                (Usize(l), Usize(r)) => checked_binops!(Usize, l, r),
+                (l, r) => return type_confusion(&format!("operands of {:?}", op), (l, r)),
+            }
+        };
+
+        let mut tuple = WrappedTuple::with_size(2);
+        tuple.data[0] = value;
+        // false == no error
+        tuple.data[1] = Bool(overflow);
+        self.stack.push(StackData::Value(WrappedValue::Tuple(tuple)));
+        Ok(())
+    }
+
+    fn o_cast(&mut self, target: CastTarget) -> EvalResult<()> {
+        use self::WrappedValue::*;
+
+        // `as` between primitives behaves the same on the host
+        macro_rules! cast {
+            ($x:expr) => ({
+                match target {
+                    CastTarget::Int(IntTy::I8) => I8($x as i8),
+                    CastTarget::Int(IntTy::I16) => I16($x as i16),
+                    CastTarget::Int(IntTy::I32) => I32($x as i32),
+                    CastTarget::Int(IntTy::I64) => I64($x as i64),
+                    CastTarget::Int(IntTy::Is) => Isize($x as isize),
+                    CastTarget::Uint(UintTy::U8) => U8($x as u8),
+                    CastTarget::Uint(UintTy::U16) => U16($x as u16),
+                    CastTarget::Uint(UintTy::U32) => U32($x as u32),
+                    CastTarget::Uint(UintTy::U64) => U64($x as u64),
+                    CastTarget::Uint(UintTy::Us) => Usize($x as usize),
+                    CastTarget::Float(FloatTy::F32) => F32($x as f32),
+                    CastTarget::Float(FloatTy::F64) => F64($x as f64),
+                    // only u8 can be cast to char
+                    CastTarget::Char => Char($x as u8 as char),
+                    CastTarget::Ptr | CastTarget::FatPtr => {
+                        let cast = format!("CAST({:?}) of the integer {:?}", target, $x);
+                        return Err(ErrorKind::UnsupportedOpcode(cast));
+                    },
+                }
+            })
+        }
+
+        let value = try!(self.pop_stack_value());
+        if target == CastTarget::Ptr || target == CastTarget::FatPtr {
+            let val = try!(self.cast_pointer(value, target));
+            self.stack.push(StackData::Value(val));
+            return Ok(());
+        }
+
+        let val = match value {
+            I8(x) => cast!(x),
+            I16(x) => cast!(x),
+            I32(x) => cast!(x),
+            I64(x) => cast!(x),
+            Isize(x) => cast!(x),
+            U8(x) => cast!(x),
+            U16(x) => cast!(x),
+            U32(x) => cast!(x),
+            U64(x) => cast!(x),
+            Usize(x) => cast!(x),
+            F32(x) => cast!(x),
+            F64(x) => cast!(x),
+            Bool(b) => cast!(b as u8),
+            Char(c) => cast!(c as u32),
+            value => {
+                let cast = format!("CAST({:?}) of {:?}", target, value);
+                return Err(ErrorKind::UnsupportedOpcode(cast));
+            },
+        };
+        self.stack.push(StackData::Value(val));
+        Ok(())
+    }
+
+    // A reference becomes a raw pointer with the same address. Casting a
+    // pointer to a slice to a thin pointer results in a pointer to its first
+    // element, like `<[T]>::as_ptr`.
+    fn cast_pointer(&mut self, value: WrappedValue<'a>, target: CastTarget) -> EvalResult<WrappedValue<'a>> {
+        match (target, value) {
+            (CastTarget::Ptr, WrappedValue::Slice(base, start, _)) => {
+                Ok(WrappedValue::Address(base.project(start)))
+            },
+            (CastTarget::Ptr, value @ WrappedValue::Fat(..)) |
+            (CastTarget::Ptr, value @ WrappedValue::Address(_)) => Ok(value.thin()),
+            (CastTarget::FatPtr, value @ WrappedValue::Slice(..)) |
+            (CastTarget::FatPtr, value @ WrappedValue::Fat(..)) => Ok(value),
+            (_, value) => {
+                let cast = format!("CAST({:?}) of {:?}", target, value);
+                Err(ErrorKind::UnsupportedOpcode(cast))
+            },
+        }
+    }
+
+    fn o_unop(&mut self, op: UnOp) -> EvalResult<()> {
+        use self::WrappedValue::*;
+
+        // negating the minimum of a signed integer is caught by an assert
+        // in front of the operation, without overflow checks it wraps
+        let val = match (op, try!(self.pop_stack_value())) {
+            (UnOp::Not, Bool(b)) => Bool(!b),
+            (UnOp::Not, I8(i)) => I8(!i),
+            (UnOp::Not, I16(i)) => I16(!i),
+            (UnOp::Not, I32(i)) => I32(!i),
+            (UnOp::Not, I64(i)) => I64(!i),
+            (UnOp::Not, Isize(i)) => Isize(!i),
+            (UnOp::Not, U8(u)) => U8(!u),
+            (UnOp::Not, U16(u)) => U16(!u),
+            (UnOp::Not, U32(u)) => U32(!u),
+            (UnOp::Not, U64(u)) => U64(!u),
+            (UnOp::Not, Usize(u)) => Usize(!u),
+
+            (UnOp::Neg, I8(i)) => I8(i.wrapping_neg()),
+            (UnOp::Neg, I16(i)) => I16(i.wrapping_neg()),
+            (UnOp::Neg, I32(i)) => I32(i.wrapping_neg()),
+            (UnOp::Neg, I64(i)) => I64(i.wrapping_neg()),
+            (UnOp::Neg, Isize(i)) => Isize(i.wrapping_neg()),
+            (UnOp::Neg, F32(f)) => F32(-f),
+            (UnOp::Neg, F64(f)) => F64(-f),
+
+            (op, value) => {
+                let unop = format!("UNOP({:?}) on {:?}", op, value);
+                return Err(ErrorKind::UnsupportedOpcode(unop));
+            },
+        };
+        self.stack.push(StackData::Value(val));
+        Ok(())
+    }
+
+    fn o_binop(&mut self, op: BinOp) -> EvalResult<()> {
+        use self::WrappedValue::*;
+        use rustc::mir::repr::BinOp::*;
+
+        let right = try!(self.pop_stack_value());
+        let left = try!(self.pop_stack_value());
+
+        // the right hand side of a shift can have a different type
+        if op == Shl || op == Shr {
+            let (val, _) = try!(shift(op, left, try!(right.shift_amount())));
+            self.stack.push(StackData::Value(val));
+            return Ok(());
+        }
+
+        // copied from miri, overflows are caught by asserts in front of the
+        // operation, so wrapping arithmetic is fine here
+        macro_rules! int_binops {
             ($v:ident, $l:ident, $r:ident) => ({
                 match op {
-                    Add    => $v($l + $r),
-                    Sub    => $v($l - $r),
-                    Mul    => $v($l * $r),
-                    Div    => $v($l / $r),
-                    Rem    => $v($l % $r),
+                    Add    => $v($l.wrapping_add($r)),
+                    Sub    => $v($l.wrapping_sub($r)),
+                    Mul    => $v($l.wrapping_mul($r)),
+                    Div    => $v($l.wrapping_div($r)),
+                    Rem    => $v($l.wrapping_rem($r)),
                     BitXor => $v($l ^ $r),
                     BitAnd => $v($l & $r),
                     BitOr  => $v($l | $r),
 
-                    // TODO(solson): Can have differently-typed RHS.
-                    Shl => $v($l << $r),
-                    Shr => $v($l >> $r),
+                    Shl | Shr => unreachable!(),
+
+                    Eq => Bool($l == $r),
+                    Ne => Bool($l != $r),
+                    Lt => Bool($l < $r),
+                    Le => Bool($l <= $r),
+                    Gt => Bool($l > $r),
+                    Ge => Bool($l >= $r),
+                }
+            })
+        }
+
+
+        macro_rules! float_binops {
+            ($v:ident, $l:ident, $r:ident) => ({
+                match op {
+                    Add => $v($l + $r),
+                    Sub => $v($l - $r),
+                    Mul => $v($l * $r),
+                    Div => $v($l / $r),
+                    Rem => $v($l % $r),
 
                     Eq => Bool($l == $r),
                     Ne => Bool($l != $r),
@@ -859,16 +2390,63 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                     Le => Bool($l <= $r),
                     Gt => Bool($l > $r),
                     Ge => Bool($l >= $r),
+
+                    BitXor | BitAnd | BitOr | Shl | Shr => {
+                        let binop = format!("BINOP({:?}) on floats", op);
+                        return Err(ErrorKind::UnsupportedOpcode(binop));
+                    },
                 }
             })
         }
 
+        macro_rules! compare {
+            ($l:expr, $r:expr) => ({
+                match op {
+                    Eq => Bool($l == $r),
+                    Ne => Bool($l != $r),
+                    Lt => Bool($l < $r),
+                    Le => Bool($l <= $r),
+                    Gt => Bool($l > $r),
+                    Ge => Bool($l >= $r),
+                    _ => {
+                        let binop = format!("BINOP({:?}) on {:?} {:?}", op, $l, $r);
+                        return Err(ErrorKind::UnsupportedOpcode(binop));
+                    },
+                }
+            })
+        }
 
         let val = StackData::Value(match(left, right) {
+            (I8(l), I8(r)) => int_binops!(I8, l, r),
+            (I16(l), I16(r)) => int_binops!(I16, l, r),
+            (I32(l), I32(r)) => int_binops!(I32, l, r),
             (I64(l), I64(r)) => int_binops!(I64, l, r),
+            (Isize(l), Isize(r)) => int_binops!(Isize, l, r),
+            (U8(l), U8(r)) => int_binops!(U8, l, r),
+            (U16(l), U16(r)) => int_binops!(U16, l, r),
+            (U32(l), U32(r)) => int_binops!(U32, l, r),
             (U64(l), U64(r)) => int_binops!(U64, l, r),
             (Usize(l), Usize(r)) => int_binops!(Usize, l, r),
 
+            (F32(l), F32(r)) => float_binops!(F32, l, r),
+            (F64(l), F64(r)) => float_binops!(F64, l, r),
+
+            (Char(l), Char(r)) => compare!(l, r),
+
+            // pointers into the same array are ordered by the index
+            (Address(l), Address(r)) => {
+                match (op, element_indices(&l, &r)) {
+                    (Eq, _) => Bool(l == r),
+                    (Ne, _) => Bool(l != r),
+                    (_, Some((l, r))) => compare!(l, r),
+                    (_, None) => {
+                        let message = format!("{:?} and {:?} point into different arrays", l, r);
+                        return Err(ErrorKind::InvalidPointer(message));
+                    },
+                }
+            },
+            (Str(l), Str(r)) => compare!(&*l, &*r),
+
             // copied from miri
             (Bool(l), Bool(r)) => {
                 Bool(match op {
@@ -881,31 +2459,100 @@ impl<'p, 'a, 'cx> Interpreter<'p, 'a, 'cx> {
                     BitOr => l | r,
                     BitXor => l ^ r,
                     BitAnd => l & r,
-                    Add | Sub | Mul | Div | Rem | Shl | Shr =>
-                        panic!("invalid binary operation on booleans: {:?}", op),
+                    Add | Sub | Mul | Div | Rem | Shl | Shr => {
+                        let binop = format!("BINOP({:?}) on booleans", op);
+                        return Err(ErrorKind::UnsupportedOpcode(binop));
+                    },
                 })
 
             },
 
-            (l, r) => {
-                println!("XXX: {:?} {:?}", l, r);
-                unimplemented!();
-            }
+            (l, r) => return type_confusion(&format!("operands of {:?}", op), (l, r)),
         });
         self.stack.push(val);
+        Ok(())
+    }
+}
+
+// the element of the array a Load points to, or of a slice
+fn element_address<'tcx>(array: StackData<'tcx>, index: usize) -> EvalResult<Address<'tcx>> {
+    match array {
+        StackData::Pointer(address) => Ok(address.project(index)),
+        StackData::Value(WrappedValue::Slice(base, start, _)) => Ok(base.project(start + index)),
+        other => type_confusion("array", other),
+    }
+}
+
+// the indices of two pointers to elements of the same array
+fn element_indices(l: &Address, r: &Address) -> Option<(usize, usize)> {
+    if let (&Address::Path(ref l_root, ref l_steps), &Address::Path(ref r_root, ref r_steps)) = (l, r) {
+        let (l_last, l_array) = l_steps.split_last().unwrap();
+        let (r_last, r_array) = r_steps.split_last().unwrap();
+        if l_root == r_root && l_array == r_array {
+            return Some((*l_last, *r_last));
+        }
+    }
+    None
+}
+
+// Option<T> as an enum value, variant 0 is None
+fn option<'tcx>(value: Option<WrappedValue<'tcx>>) -> WrappedValue<'tcx> {
+    let data = match value {
+        Some(value) => {
+            let mut data = WrappedTuple::with_size(1);
+            data.data[0] = value;
+            data
+        },
+        None => WrappedTuple::with_size(0),
+    };
+    let variant = if data.data.is_empty() { 0 } else { 1 };
+    WrappedValue::Enum(WrappedEnum { variant: variant, data: data })
+}
+
+// Shifts an integer by `amount` bits. Returns the result and whether `amount`
+// exceeds the bit width of the integer, in which case the shift is masked.
+fn shift<'tcx>(op: BinOp, value: WrappedValue<'tcx>, amount: u32)
+               -> EvalResult<(WrappedValue<'tcx>, bool)> {
+    use self::WrappedValue::*;
+
+    macro_rules! shift_op {
+        ($v:ident, $x:ident) => ({
+            let (val, overflow) = match op {
+                BinOp::Shl => $x.overflowing_shl(amount),
+                BinOp::Shr => $x.overflowing_shr(amount),
+                _ => return Err(ErrorKind::UnsupportedOpcode(format!("{:?} as a shift", op))),
+            };
+            Ok(($v(val), overflow))
+        })
+    }
 
+    match value {
+        I8(x) => shift_op!(I8, x),
+        I16(x) => shift_op!(I16, x),
+        I32(x) => shift_op!(I32, x),
+        I64(x) => shift_op!(I64, x),
+        Isize(x) => shift_op!(Isize, x),
+        U8(x) => shift_op!(U8, x),
+        U16(x) => shift_op!(U16, x),
+        U32(x) => shift_op!(U32, x),
+        U64(x) => shift_op!(U64, x),
+        Usize(x) => shift_op!(Usize, x),
+        _ => type_confusion("integer", value),
     }
 }
 
+/// Runs `main`, returns the panic the guest ended with, if any.
 pub fn interpret<'a, 'tcx>(
         program: &'a mut Program<'a, 'tcx>,
-        main: DefId,
+        main: Instance<'a>,
         tcx: TyCtxt<'a, 'tcx, 'tcx>,
         map: &MirMap<'tcx>,
-        internals: &BTreeMap<DefId, String>
-        ){
+        internals: &BTreeMap<DefId, String>,
+        stack_limit: usize,
+        print_traces: bool
+        ) -> Result<Option<Panic>, InterpreterError> {
 
-    let mut interpreter = Interpreter::new(program, internals);
+    let mut interpreter = Interpreter::new(program, internals, stack_limit, print_traces);
 
-    interpreter.run(main);
+    interpreter.run(main)
 }
@@ -7,17 +7,17 @@
 
 // TODO: Hack an output function.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 pub use rustc::mir::repr::BorrowKind;
 
 pub use rustc::mir::repr::{
     BasicBlock, BasicBlockData, Mir,
-    BinOp, Constant, Literal, Operand,
+    BinOp, UnOp, Constant, Literal, Operand,
     Lvalue, Rvalue,
     Statement, StatementKind, Terminator, TerminatorKind,
     ProjectionElem, AggregateKind,
-    Field, CastKind
+    Field, CastKind, AssertMessage
 };
 
 use rustc::mir::mir_map::MirMap;
@@ -26,51 +26,162 @@ use rustc::middle::const_val::ConstVal;
 use rustc::hir::map::Node;
 use rustc::hir::def_id::DefId;
 
-use rustc::ty::{TyCtxt, AdtKind, VariantKind};
+use rustc::ty::{TyCtxt, Ty, AdtKind, VariantKind, TypeFoldable, LvaluePreference, ClosureKind};
+use rustc::ty::subst::{Subst, Substs};
+use rustc::ty::TypeVariants::{TyInt, TyUint, TyFloat, TyClosure, TyTuple, TyArray, TySlice, TyTrait, TyStr, TyChar, TyFnDef,
+                               TyFnPtr, TyRawPtr};
+use syntax::ast::{IntTy, UintTy, FloatTy};
+use syntax::abi::Abi;
+use syntax::codemap::{Span, DUMMY_SP};
 
-use rustc_const_math::{Us32, Us64};
+use rustc_const_math::{Us32, Us64, Is32, Is64};
 
 use std::ops::{Deref, DerefMut};
 
 use std::rc::Rc;
+use std::iter;
 
 use rustc_data_structures::indexed_vec::Idx;
 
 
 // use std::cell::RefCell;
 // use rustc_const_math::ConstInt;
-// use syntax::parse::token::InternedString;
+use syntax::parse::token::InternedString;
 
 pub mod interpret;
+mod resolve;
 
-pub type Function<'tcx> = Vec<OpCode<'tcx>>;
+use self::interpret::{WrappedValue, Metadata};
+
+/// The bytecode of a function or trace, with the MIR span of each opcode.
+#[derive(Clone, Debug)]
+pub struct Function<'tcx> {
+    opcodes: Vec<OpCode<'tcx>>,
+    // spans[pc] is the span of the statement or terminator opcodes[pc] was
+    // compiled from
+    spans: Vec<Span>,
+}
+
+impl<'tcx> Function<'tcx> {
+    pub fn new() -> Self {
+        Function { opcodes: Vec::new(), spans: Vec::new() }
+    }
+
+    pub fn span(&self, pc: usize) -> Span {
+        self.spans[pc]
+    }
+
+    // the opcodes pushed since the last call come from `span`
+    fn set_span(&mut self, span: Span) {
+        let n = self.opcodes.len() - self.spans.len();
+        self.spans.extend(iter::repeat(span).take(n));
+    }
+}
+
+impl<'tcx> Deref for Function<'tcx> {
+    type Target = Vec<OpCode<'tcx>>;
+
+    fn deref(&self) -> &Vec<OpCode<'tcx>> {
+        &self.opcodes
+    }
+}
+
+impl<'tcx> DerefMut for Function<'tcx> {
+    fn deref_mut(&mut self) -> &mut Vec<OpCode<'tcx>> {
+        &mut self.opcodes
+    }
+}
+
+/// A function together with the substitutions for its type parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Instance<'tcx> {
+    pub def_id: DefId,
+    pub substs: &'tcx Substs<'tcx>,
+}
+
+impl<'tcx> Instance<'tcx> {
+    pub fn new(def_id: DefId, substs: &'tcx Substs<'tcx>) -> Self {
+        Instance { def_id: def_id, substs: substs }
+    }
+
+    // instance of a function without type parameters
+    pub fn mono<'a>(tcx: TyCtxt<'a, 'tcx, 'tcx>, def_id: DefId) -> Self {
+        Instance::new(def_id, Substs::empty(tcx))
+    }
+}
+
+/// Methods of a trait object, indexed by their vtable index, and the drop
+/// glue of the concrete type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vtable<'tcx> {
+    pub methods: Vec<Option<Instance<'tcx>>>,
+    // `Drop::drop` of the concrete type, its fields are dropped like those
+    // of any other value
+    pub drop: Option<Instance<'tcx>>,
+}
 
 // pub type KrateTree<'a> = BTreeMap<u32, BTreeMap<u32, Function<'a>>>;
-pub type KrateTree<'a> = BTreeMap<DefId, Rc<Function<'a>>>;
+pub type KrateTree<'a> = HashMap<Instance<'a>, Rc<Function<'a>>>;
 //Program: krate_id -> node_id -> Function
 // pub type Program<'a> = BTreeMap<u32, BTreeMap<u32, Function<'a>>>;
 
 pub struct Program<'a, 'tcx: 'a> {
     context: &'a Context<'a, 'tcx>,
-    pub krates: KrateTree<'a>
+    pub krates: KrateTree<'a>,
+    // bodies of the promoted constants of each function, by promoted index
+    promoted: HashMap<Instance<'a>, Vec<Rc<Function<'a>>>>,
 }
 
 impl<'a, 'tcx> Program<'a, 'tcx> {
     fn new(context: &'a Context<'a, 'tcx>) -> Program<'a, 'tcx> {
-        Program {context: context, krates: BTreeMap::new() }
+        Program {context: context, krates: HashMap::new(), promoted: HashMap::new() }
     }
 
-    fn get_func<'b>(&'b mut self, def_id: DefId) -> Rc<Function<'a>> {
-        let context = &self.context;
+    fn get_func<'b>(&'b mut self, instance: Instance<'a>) -> Rc<Function<'a>> {
+        if !self.krates.contains_key(&instance) {
+            // println!("load function {:?}", instance);
+            let context = self.context;
+            let def_id = instance.def_id;
+
+            // local functions which aren't items, e.g. closures
+            if let Some(mir) = context.map.map.get(&def_id) {
+                self.load(instance, mir);
+            } else {
+                let cs = &context.tcx.sess.cstore;
+                match cs.maybe_get_item_mir(context.tcx, def_id) {
+                    Some(mir) => self.load(instance, &mir),
+                    None => self.load_without_mir(instance),
+                }
+            }
+        }
+        self.krates[&instance].clone()
+    }
+
+    fn get_promoted(&self, instance: Instance<'a>, index: usize) -> Rc<Function<'a>> {
+        self.promoted[&instance][index].clone()
+    }
+
+    // compiles a function together with its promoted constants
+    fn load(&mut self, instance: Instance<'a>, mir: &Mir<'a>) {
+        let promoted = mir.promoted.iter().map(|promoted| {
+            Rc::new(self.context.mir_to_bytecode(promoted, instance))
+        }).collect();
+        self.promoted.insert(instance, promoted);
 
-        self.krates.entry(def_id).or_insert_with(|| {
-            // println!("load function {:?}", def_id);
-            let cs = &context.tcx.sess.cstore;
-            let mir = cs.maybe_get_item_mir(context.tcx, def_id).unwrap_or_else(||{
-                panic!("no mir for {:?}", def_id);
-            });
-            Rc::new(context.mir_to_bytecode(&mir))
-        }).clone()
+        let func = self.context.mir_to_bytecode(mir, instance);
+        self.krates.insert(instance, Rc::new(func));
+    }
+
+    // e.g. a method of a trait the compiler implements, which fails when it's
+    // called instead of stopping the host
+    fn load_without_mir(&mut self, instance: Instance<'a>) {
+        let name = self.context.tcx.item_path_str(instance.def_id);
+        let mut func = Function::new();
+        func.opcodes.push(OpCode::TODO_S(format!("call of {}, which has no MIR", name)));
+        func.set_span(DUMMY_SP);
+
+        self.promoted.insert(instance, Vec::new());
+        self.krates.insert(instance, Rc::new(func));
     }
 }
 
@@ -91,6 +202,7 @@ impl<'a, 'tcx> Program<'a, 'tcx> {
 
 #[derive(Debug, Clone)]
 pub enum Var {
+    Return,
     Arg,
     Var,
     Tmp,
@@ -114,35 +226,94 @@ pub enum OpCode<'tcx>{
 
 
     Const(Constant<'tcx>),
-    Static(DefId),
-    LoadFunc(DefId),
+    Static(Instance<'tcx>),
+    StoreStatic(Instance<'tcx>),
+    LoadConst(Instance<'tcx>),
+    // (function, promoted index), pushes the address of the promoted value
+    LoadPromoted(Instance<'tcx>, usize),
+    LoadFunc(Instance<'tcx>),
+    // load the method with the vtable index from the receiver of the call
+    VIRTUAL(usize),
     Len,
     AssignIndex,
     GetIndex,
     ArgCount(usize),
     Call,
+    // spread the tuple on the stack into its fields
+    UNTUPLE(usize),
 
-    UnsignedInteger(u64),
+    UnsignedInteger(u64, UintTy),
     Usize(usize),
-    SignedInteger(i64),
-    Float(f64),
+    SignedInteger(i64, IntTy),
+    Float(f64, FloatTy),
+    Char(char),
+    Str(InternedString),
     Bool(bool),
 
     Repeat(usize),
 
     BORROW(BorrowKind),
+    // allocate an uninitialized box on the heap
+    BOX,
+    // read the value a Load points to and mark the location as moved out
+    MOVE,
+    // Vec methods, which get the Vec by reference like the originals
+    VEC_NEW,
+    VEC_FROM_BOX,
+    VEC_PUSH,
+    VEC_POP,
+    VEC_LEN,
+    VEC_INDEX,
+    VEC_ITER,
+    ITER_NEXT,
 
     DEREF,
     DEREF_STORE,
 
+    // turns the pointer on the stack into a fat pointer
+    UNSIZE(Metadata<'tcx>),
+    // (offset, from_end), pushes the index of an element of the array or
+    // slice on the stack below it, for GetIndex and AssignIndex
+    CONSTANT_INDEX(usize, bool),
+    // (from, to), the slice without `from` elements at the start and `to`
+    // elements at the end
+    SUBSLICE(usize, usize),
+    // slices the slice or Vec below the range on the stack
+    SLICE_RANGE(RangeKind),
+    // the slice of the elements of a Vec
+    VEC_SLICE,
+
+    // intrinsics for raw pointers
+    PTR_OFFSET,
+    // (src, dst, count)
+    PTR_COPY,
+    PTR_WRITE,
+    // the value of `mem::uninitialized`
+    UNINIT,
+
     BINOP(BinOp),
+    UNOP(UnOp),
+    CAST(CastTarget),
     CBINOP(BinOp),
 
-    RETURN_POINTER,
+    // (expected, message), raises a guest panic if the condition on the
+    // stack doesn't match
+    ASSERT(bool, AssertKind),
+    // raise a guest panic with the &str on the stack as message
+    PANIC,
+    // raise a guest panic with the message of the fmt::Arguments on the stack
+    PANIC_FMT,
 
     //Terminator
     _Goto(BasicBlock),
     _GotoIf(BasicBlock),
+    // targets are indexed by variant
+    _Switch(Vec<BasicBlock>),
+    // the last target is taken if none of the values match
+    _SwitchInt(Vec<WrappedValue<'tcx>>, Vec<BasicBlock>),
+    // continue at the cleanup block while a panic is in flight, without a
+    // cleanup block the function is left
+    _Unwind(Option<BasicBlock>),
 
     RETURN,
     RESUME,
@@ -150,6 +321,13 @@ pub enum OpCode<'tcx>{
     TUPLE(usize),
     VEC(usize),
 
+    // (variant, number of fields), fields are assigned with TUPLE_ASSIGN
+    VARIANT(usize, usize),
+
+    // marks the aggregate on top of the stack as having a `Drop` impl
+    DESTRUCTOR(Instance<'tcx>),
+    DROP,
+
     //XXX: used for creation of tuple
     TUPLE_ASSIGN(usize),
 
@@ -165,23 +343,83 @@ pub enum OpCode<'tcx>{
     JUMP_REL(i32),
     JUMP_REL_IF(i32),
 
+    SWITCH(Vec<usize>),
+    SWITCH_REL(Vec<i32>),
+
+    SWITCH_INT(Vec<WrappedValue<'tcx>>, Vec<usize>),
+    SWITCH_INT_REL(Vec<WrappedValue<'tcx>>, Vec<i32>),
+
+    UNWIND(Option<usize>),
+    UNWIND_REL(Option<i32>),
+
     Pop,
 
     StackFrame(usize),
+    // a Call in a trace, with the pc of the call in the caller
+    TracedCall(usize),
 
     Guard(Guard<'tcx>),
     Debug(usize),
 
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CastTarget {
+    Int(IntTy),
+    Uint(UintTy),
+    Float(FloatTy),
+    Char,
+    // raw pointer to a sized value
+    Ptr,
+    // raw pointer to a slice, str or trait object
+    FatPtr,
+}
+
+// the range types which can index a slice
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RangeKind {
+    Range,
+    RangeFrom,
+    RangeTo,
+    RangeFull,
+}
+
+#[derive(Clone, Debug)]
+pub enum AssertKind {
+    // len and index are pushed before the condition
+    BoundsCheck,
+    Math(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub enum GuardValue<'tcx> {
+    Bool(bool),
+    Variant(usize),
+    Value(WrappedValue<'tcx>),
+    // fails while a panic is in flight, doesn't check a value
+    NoPanic,
+}
+
 #[derive(Clone, Debug)]
 pub struct Guard<'a> {
-    pub expected: bool,
+    pub expected: GuardValue<'a>,
     pub recovery: Rc<Function<'a>>,
     pub pc: usize,
 }
 
 
+// What a call of a `Fn*` trait method dispatches to.
+enum FnTraitCallee<'a> {
+    // the closure body, which takes the environment before the arguments
+    Closure(Instance<'a>),
+    // a fn item doesn't take the receiver
+    FnItem(Instance<'a>),
+    // the receiver is the fn pointer which is called
+    FnPtr,
+    // a closure behind a trait object, the index of its body in the vtable
+    Object(usize),
+}
+
 pub struct Context<'a, 'tcx: 'a> {
     pub tcx: TyCtxt<'a, 'tcx, 'tcx>,
     pub map: &'a MirMap<'tcx>
@@ -190,10 +428,12 @@ pub struct Context<'a, 'tcx: 'a> {
 
 impl<'a, 'tcx> Context<'a, 'tcx> {
 
-    pub fn mir_to_bytecode(&'a self, func: &Mir<'a>) -> Function<'a> {
+    /// Compiles `func` for `instance`, its type parameters are replaced by the
+    /// substs of the instance.
+    pub fn mir_to_bytecode(&'a self, func: &Mir<'a>, instance: Instance<'a>) -> Function<'a> {
         let blocks = func.basic_blocks().iter().map(
             |bb| {
-                let mut gen = BlockGen::new(self.tcx);
+                let mut gen = BlockGen::new(self.tcx, func, instance);
                 gen.analyse_block(bb);
                 gen.opcodes
             }).collect();
@@ -209,30 +449,46 @@ impl<'a, 'tcx> Context<'a, 'tcx> {
             n += block.len();
         }
 
-        let var_offset = func.arg_decls.len();
+        // slot 0 of a frame holds the return value, the arguments follow
+        let arg_offset = 1;
+        let var_offset = arg_offset + func.arg_decls.len();
         let tmp_offset = var_offset + func.var_decls.len();
 
         let mut opcodes = Vec::new();
+        let mut spans = Vec::new();
 
         for block in blocks {
-            for opcode in block.iter() {
+            for (opcode, &span) in block.iter().zip(&block.spans) {
                 let oc: OpCode = match *opcode {
                     OpCode::_Goto(ref target) => OpCode::JUMP(indicies[target.index()]),
                     OpCode::_GotoIf(ref target) => OpCode::JUMP_IF(indicies[target.index()]),
-
-                    OpCode::Load(Var::Arg, n) => OpCode::LoadLocal(n as usize),
+                    OpCode::_Switch(ref targets) => OpCode::SWITCH(
+                        targets.iter().map(|target| indicies[target.index()]).collect()),
+                    OpCode::_Unwind(ref cleanup) => OpCode::UNWIND(
+                        cleanup.map(|target| indicies[target.index()])),
+                    OpCode::_SwitchInt(ref values, ref targets) => OpCode::SWITCH_INT(
+                        values.clone(),
+                        targets.iter().map(|target| indicies[target.index()]).collect()),
+
+                    OpCode::Load(Var::Return, _) => OpCode::LoadLocal(0),
+                    OpCode::Load(Var::Arg, n) => OpCode::LoadLocal(arg_offset + n as usize),
                     OpCode::Load(Var::Var, n) => OpCode::LoadLocal(var_offset + n as usize),
                     OpCode::Load(Var::Tmp, n) => OpCode::LoadLocal(tmp_offset + n as usize),
-                    OpCode::Store(Var::Arg, n) => OpCode::StoreLocal(n as usize),
+                    OpCode::Store(Var::Return, _) => OpCode::StoreLocal(0),
+                    OpCode::Store(Var::Arg, n) => OpCode::StoreLocal(arg_offset + n as usize),
                     OpCode::Store(Var::Var, n) => OpCode::StoreLocal(var_offset + n as usize),
                     OpCode::Store(Var::Tmp, n) => OpCode::StoreLocal(tmp_offset + n as usize),
                     _ => opcode.clone(),
                 };
                 opcodes.push(oc);
+                spans.push(span);
             }
         }
 
-        let mut opcodes_rel = vec![OpCode::StackFrame(tmp_offset+func.temp_decls.len())];
+        // the frame is set up before the first statement of the function
+        let mut opcodes_rel = Function::new();
+        opcodes_rel.push(OpCode::StackFrame(tmp_offset+func.temp_decls.len()));
+        opcodes_rel.set_span(func.span);
 
         for (ii, opcode) in opcodes.iter_mut().enumerate() {
             let i = ii as i32;
@@ -253,11 +509,24 @@ impl<'a, 'tcx> Context<'a, 'tcx> {
                         Some(OpCode::JUMP_REL_IF(dist))
                     // }
                 },
+                OpCode::SWITCH(ref targets) => {
+                    Some(OpCode::SWITCH_REL(
+                        targets.iter().map(|&target| target as i32 - i).collect()))
+                },
+                OpCode::UNWIND(cleanup) => {
+                    Some(OpCode::UNWIND_REL(cleanup.map(|target| target as i32 - i)))
+                },
+                OpCode::SWITCH_INT(ref values, ref targets) => {
+                    Some(OpCode::SWITCH_INT_REL(
+                        values.clone(),
+                        targets.iter().map(|&target| target as i32 - i).collect()))
+                },
                 _ => Some(opcode.clone())
             };
 
             if let Some(op) = oc {
                 opcodes_rel.push(op);
+                opcodes_rel.set_span(spans[ii]);
             }
         }
 
@@ -288,22 +557,33 @@ impl<'a, 'tcx> Context<'a, 'tcx> {
 //     }
 // }
 
-struct BlockGen<'a, 'tcx: 'a>{
+struct BlockGen<'b, 'a: 'b, 'tcx: 'a>{
     opcodes: Function<'a>,
-    tcx: TyCtxt<'a, 'tcx, 'tcx>
+    tcx: TyCtxt<'a, 'tcx, 'tcx>,
+    mir: &'b Mir<'a>,
+    // the function being compiled, its substs replace the type parameters
+    instance: Instance<'a>,
 }
 
-impl<'a, 'tcx> BlockGen<'a, 'tcx> {
+impl<'b, 'a, 'tcx> BlockGen<'b, 'a, 'tcx> {
 
-    fn new(tcx: TyCtxt<'a, 'tcx, 'tcx>) -> Self {
-        BlockGen{ opcodes: Vec::new(), tcx: tcx }
+    fn new(tcx: TyCtxt<'a, 'tcx, 'tcx>, mir: &'b Mir<'a>, instance: Instance<'a>) -> Self {
+        BlockGen{ opcodes: Function::new(), tcx: tcx, mir: mir, instance: instance }
+    }
+
+    fn monomorphize<T>(&self, value: &T) -> T where T: TypeFoldable<'a> {
+        let substituted = value.subst(self.tcx, self.instance.substs);
+        self.tcx.normalize_associated_type(&substituted)
     }
 
     fn analyse_block(&mut self, block: &BasicBlockData<'a>) {
         for statement in &block.statements {
             self.analyse_statement(statement);
+            self.opcodes.set_span(statement.source_info.span);
         }
-        self.analyse_terminator(block.terminator());
+        let terminator = block.terminator();
+        self.analyse_terminator(terminator);
+        self.opcodes.set_span(terminator.source_info.span);
     }
 
     fn analyse_statement(&mut self, statement: &Statement<'a>) {
@@ -317,8 +597,8 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
         let opcode = match *lvalue {
             Lvalue::Var(n)  => OpCode::Store(Var::Var, n.index()),
             Lvalue::Temp(n) => OpCode::Store(Var::Tmp, n.index()),
-            Lvalue::Arg(_n)  => unreachable!(),
-            Lvalue::Static(..)  => OpCode::TODO("assign static"),
+            Lvalue::Arg(n)  => OpCode::Store(Var::Arg, n.index()),
+            Lvalue::Static(def_id)  => OpCode::StoreStatic(Instance::mono(self.tcx, def_id)),
 
             Lvalue::Projection(ref proj) => {
                 match proj.elem {
@@ -349,6 +629,15 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                         OpCode::AssignIndex
                     },
 
+                    // x[1] = z; or x[x.len() - 1] = z; in slice patterns
+                    ProjectionElem::ConstantIndex{ offset, from_end, .. } => {
+                        let opcode = self.load_lvalue(&proj.base);
+                        self.opcodes.push(opcode);
+                        self.opcodes.push(OpCode::CONSTANT_INDEX(offset as usize, from_end));
+
+                        OpCode::AssignIndex
+                    },
+
                     _ => OpCode::TODO_S(format!("assign projection {:?}", proj.elem)),
                 }
                 // proj.base: Lvalue
@@ -356,8 +645,7 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                 // OpCode::TODO("assign projections")
             },
 
-            // ignore, value is just left on the stack
-            Lvalue::ReturnPointer => OpCode::RETURN_POINTER,
+            Lvalue::ReturnPointer => OpCode::Store(Var::Return, 0),
 
             // _ => OpCode::TODO("assign_to"),
 
@@ -375,64 +663,155 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                 self.opcodes.push(OpCode::_GotoIf(*bb1));
                 OpCode::_Goto(*bb2)
             },
+            TerminatorKind::Switch{ref discr, adt_def: _, ref targets} => {
+                let opcode = self.load_lvalue(discr);
+                self.opcodes.push(opcode);
+                OpCode::_Switch(targets.clone())
+            },
+            TerminatorKind::SwitchInt{ref discr, switch_ty, ref values, ref targets} => {
+                let opcode = self.load_lvalue(discr);
+                self.opcodes.push(opcode);
+
+                let values: Result<Vec<_>, _> = values.iter().map(|value| {
+                    match self.unpack_const_val(value, switch_ty) {
+                        todo @ OpCode::TODO_S(_) => Err(todo),
+                        constant => Ok(WrappedValue::from_constant(&constant)),
+                    }
+                }).collect();
+                match values {
+                    Ok(values) => OpCode::_SwitchInt(values, targets.clone()),
+                    Err(todo) => todo,
+                }
+            },
             TerminatorKind::Return => OpCode::RETURN,
             TerminatorKind::Resume => OpCode::RESUME,
 
-            TerminatorKind::Call{ref func, ref args, ref destination, ..} => {
-
-                // self.opcodes.push(OpCode::TODO("Load Args"));
+            TerminatorKind::Call{ref func, ref args, ref destination, cleanup}
+                    if self.native_method(func, args).is_some() => {
                 for arg in args {
                     self.rvalue_operand(arg);
                 }
-                self.opcodes.push(OpCode::ArgCount(args.len()));
-
-                self.rvalue_operand(func);
+                let opcodes = self.native_method(func, args).unwrap();
+                self.opcodes.extend(opcodes);
+                self.opcodes.push(OpCode::_Unwind(cleanup));
+                self.call_return(destination)
+            },
 
-                match destination.as_ref() {
-                    Some(dest) => {
-                        self.opcodes.push(OpCode::Call);
-                        self.assign_to(&dest.0);
-                        OpCode::_Goto(dest.1)
-                    },
-                    None => {
-                        OpCode::TODO("NO RETURN")
+            TerminatorKind::Call{ref func, ref args, ref destination, cleanup} => {
+
+                if let Some((callee, by_value)) = self.fn_trait_callee(func) {
+                    // (env, (a, b)), but the closure body takes (env, a, b)
+                    let arity = match self.monomorphize(&args[1].ty(self.mir, self.tcx)).sty {
+                        TyTuple(ref tys) => tys.len(),
+                        ref ty => {
+                            let message = format!("closure call with untupled arguments {:?}", ty);
+                            self.opcodes.push(OpCode::TODO_S(message));
+                            return;
+                        },
+                    };
+
+                    match callee {
+                        FnTraitCallee::Closure(closure) => {
+                            self.closure_receiver(&args[0], closure, by_value);
+                            self.rvalue_operand(&args[1]);
+                            self.opcodes.push(OpCode::UNTUPLE(arity));
+                            self.opcodes.push(OpCode::ArgCount(1 + arity));
+                            self.opcodes.push(OpCode::LoadFunc(closure));
+                        },
+                        FnTraitCallee::FnItem(instance) => {
+                            self.rvalue_operand(&args[1]);
+                            self.opcodes.push(OpCode::UNTUPLE(arity));
+                            self.opcodes.push(OpCode::ArgCount(arity));
+                            self.opcodes.push(OpCode::LoadFunc(instance));
+                        },
+                        FnTraitCallee::FnPtr => {
+                            self.rvalue_operand(&args[1]);
+                            self.opcodes.push(OpCode::UNTUPLE(arity));
+                            self.opcodes.push(OpCode::ArgCount(arity));
+                            self.rvalue_operand(&args[0]);
+                            if !by_value {
+                                self.opcodes.push(OpCode::DEREF);
+                            }
+                        },
+                        FnTraitCallee::Object(idx) => {
+                            self.rvalue_operand(&args[0]);
+                            self.rvalue_operand(&args[1]);
+                            self.opcodes.push(OpCode::UNTUPLE(arity));
+                            self.opcodes.push(OpCode::ArgCount(1 + arity));
+                            self.opcodes.push(OpCode::VIRTUAL(idx));
+                        },
                     }
+                } else {
+                    // self.opcodes.push(OpCode::TODO("Load Args"));
+                    for arg in args {
+                        self.rvalue_operand(arg);
+                    }
+                    self.opcodes.push(OpCode::ArgCount(args.len()));
+
+                    self.rvalue_operand(func);
                 }
+
+                self.opcodes.push(OpCode::Call);
+                self.opcodes.push(OpCode::_Unwind(cleanup));
+                self.call_return(destination)
                 // println!("{:?}", destination.0);
                 // OpCode::Call()
                 // OpCode::TODO("CALL")
             },
 
-            TerminatorKind::Drop{location: ref lvalue, target: _, unwind: _} => {
+            TerminatorKind::Drop{location: ref lvalue, target, unwind} => {
                 let opcode = self.load_lvalue(lvalue);
                 self.opcodes.push(opcode);
-                OpCode::TODO("Drop")
+                self.opcodes.push(OpCode::DROP);
+                self.opcodes.push(OpCode::_Unwind(unwind));
+                OpCode::_Goto(target)
             },
 
-            TerminatorKind::Assert{ref cond, expected, msg: _, target, cleanup} => {
-                // cond.as_rvalue(env);
-                // env.add(OpCode::ConstValue(R_BoxedValue::Bool(expected)));
-                // env.add(OpCode::BinOp(BinOp::Eq));
-                // env.opcodes.push(MetaOpCode::GotoIf(target));
+            TerminatorKind::DropAndReplace{location: ref lvalue, ref value, target, unwind} => {
+                let opcode = self.load_lvalue(lvalue);
+                self.opcodes.push(opcode);
+                self.opcodes.push(OpCode::DROP);
+                self.opcodes.push(OpCode::_Unwind(unwind));
 
+                self.rvalue_operand(value);
+                self.assign_to(lvalue);
                 OpCode::_Goto(target)
-                // if let Some(bb) = cleanup {
-                    // OpCode::_Goto(bb)
-                // } else {
-                    // panic!("Titanic");
-                    // OpCode::Noop
-                // }
             },
 
-            _ => {
-                println!("{:?}", terminator.kind);
-                OpCode::TODO("Terminator")
-            }
+            TerminatorKind::Assert{ref cond, expected, ref msg, target, cleanup} => {
+                let kind = match *msg {
+                    AssertMessage::BoundsCheck{ref len, ref index} => {
+                        self.rvalue_operand(len);
+                        self.rvalue_operand(index);
+                        AssertKind::BoundsCheck
+                    },
+                    AssertMessage::Math(ref err) => AssertKind::Math(err.description()),
+                };
+
+                self.rvalue_operand(cond);
+                self.opcodes.push(OpCode::ASSERT(expected, kind));
+                self.opcodes.push(OpCode::_Unwind(cleanup));
+                OpCode::_Goto(target)
+            },
+
+            _ => OpCode::TODO("Terminator"),
         };
         self.opcodes.push(op);
     }
 
 
+    // The returned value is assigned to the destination. A call without one
+    // diverges, it can only be left by unwinding.
+    fn call_return(&mut self, destination: &Option<(Lvalue<'a>, BasicBlock)>) -> OpCode<'a> {
+        match *destination {
+            Some((ref dest, target)) => {
+                self.assign_to(dest);
+                OpCode::_Goto(target)
+            },
+            None => OpCode::TODO("return from a diverging call"),
+        }
+    }
+
     fn handle_rvalue(&mut self, rvalue: &Rvalue<'a>) {
         match *rvalue {
             Rvalue::Use(ref op) => {
@@ -452,6 +831,11 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                 self.opcodes.push(OpCode::BINOP(op));
             },
 
+            Rvalue::UnaryOp(op, ref operand) => {
+                self.rvalue_operand(operand);
+                self.opcodes.push(OpCode::UNOP(op));
+            },
+
             Rvalue::Aggregate(AggregateKind::Tuple, ref vec) => {
                 self.opcodes.push(OpCode::TUPLE(vec.len()));
                 for (i, value) in vec.iter().enumerate() {
@@ -466,7 +850,7 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                 }
                 self.opcodes.push(OpCode::VEC(vec.len()));
             },
-            Rvalue::Aggregate(AggregateKind::Adt(adt_def, _size, _subst, _), ref operands) => {
+            Rvalue::Aggregate(AggregateKind::Adt(adt_def, variant, substs, _), ref operands) => {
                 /*
                     Adt (abstract data type) is an enum. Structs are enums with only one variant.
                     To check whether an adt is an enum or a struct one can use `.adt_kind`.
@@ -475,15 +859,22 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                     Variants are either VariantKind::{Struct, Tuple, Unit}
                 */
 
+                let ref variant_def = adt_def.variants[variant];
                 if adt_def.adt_kind() == AdtKind::Struct {
                     // the struct definition is the first variant
-                    let ref struct_def = adt_def.variants[0];
-                    self.opcodes.push(OpCode::TUPLE(struct_def.fields.len()));
-                    for (i, operand) in operands.iter().enumerate() {
-                        self.rvalue_operand(operand);
-                        self.opcodes.push(OpCode::TUPLE_ASSIGN(i));
-                    }
-                    // self.opcodes.push(OpCode::)
+                    self.opcodes.push(OpCode::TUPLE(variant_def.fields.len()));
+                } else {
+                    self.opcodes.push(OpCode::VARIANT(variant, variant_def.fields.len()));
+                }
+
+                if let Some(destructor) = adt_def.destructor() {
+                    let instance = Instance::new(destructor, self.monomorphize(&substs));
+                    self.opcodes.push(OpCode::DESTRUCTOR(instance));
+                }
+
+                for (i, operand) in operands.iter().enumerate() {
+                    self.rvalue_operand(operand);
+                    self.opcodes.push(OpCode::TUPLE_ASSIGN(i));
                 }
                 // println!("S: {:?}", size);
                 // for var in adt_def.variants.iter() {
@@ -492,8 +883,13 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                 // println!("{:?}", adt_def.variants.iter().map(|var| var.name).collect());
                 // self.opcodes.push(OpCode::TODO("Aggr Adt"));
             },
-            Rvalue::Aggregate(AggregateKind::Closure(_def_id, _subst), ref _aggr) => {
-                self.opcodes.push(OpCode::TODO("Aggr Closure"));
+            // the environment of a closure is the tuple of its upvars
+            Rvalue::Aggregate(AggregateKind::Closure(_def_id, _subst), ref upvars) => {
+                self.opcodes.push(OpCode::TUPLE(upvars.len()));
+                for (i, upvar) in upvars.iter().enumerate() {
+                    self.rvalue_operand(upvar);
+                    self.opcodes.push(OpCode::TUPLE_ASSIGN(i));
+                }
             },
 
             Rvalue::Ref(ref _region, ref kind, ref lvalue) => {
@@ -521,17 +917,40 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                match *kind {
                     CastKind::Unsize => {
                         // println!("unsize {:?} to {:?}", operand, ty);
-
-                        self.opcodes.push(OpCode::TODO("UNSIZE"));
+                        let source = self.monomorphize(&operand.ty(self.mir, self.tcx));
+                        let target = self.monomorphize(ty);
+                        match self.unsize_metadata(source, target) {
+                            Ok(metadata) => {
+                                self.rvalue_operand(operand);
+                                self.opcodes.push(OpCode::UNSIZE(metadata));
+                            },
+                            Err(message) => self.opcodes.push(OpCode::TODO_S(message)),
+                        }
+                    },
+                    CastKind::Misc => {
+                        match self.cast_target(self.monomorphize(ty)) {
+                            Ok(target) => {
+                                self.rvalue_operand(operand);
+                                self.opcodes.push(OpCode::CAST(target));
+                            },
+                            Err(message) => self.opcodes.push(OpCode::TODO_S(message)),
+                        }
+                    },
+                    // a fn item is loaded as the address of the function,
+                    // which is a fn pointer already
+                    CastKind::ReifyFnPointer | CastKind::UnsafeFnPointer => {
+                        self.rvalue_operand(operand);
                     },
-                    _ => unimplemented!(),
                }
             },
 
-            _ => {
-                println!("TODO-rvalue: {:?}", rvalue);
-                self.opcodes.push(OpCode::TODO("Rvalue"))
+            // `box value` allocates first, then the value is stored through
+            // a Deref projection of the box
+            Rvalue::Box(_ty) => {
+                self.opcodes.push(OpCode::BOX);
             },
+
+            _ => self.opcodes.push(OpCode::TODO("Rvalue")),
         }
     }
 
@@ -539,12 +958,19 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
        let cmd = match op {
             &Operand::Consume(ref lvalue) => {
                 let o = self.load_lvalue(lvalue);
-                // self.opcodes.push(o);
-                o
+                // values which need to be dropped are moved, so that the
+                // drop of the old location doesn't free them
+                let ty = self.monomorphize(&lvalue.ty(self.mir, self.tcx).to_ty(self.tcx));
+                if self.tcx.type_needs_drop_given_env(ty, &self.tcx.empty_parameter_environment()) {
+                    self.opcodes.push(o);
+                    OpCode::MOVE
+                } else {
+                    o
+                }
                 // OpCode::Consume
             },
             &Operand::Constant(ref constant) => {
-                if let Literal::Item{ ref def_id, .. } = constant.literal {
+                if let (&Literal::Item{ def_id, substs }, &TyFnDef(..)) = (&constant.literal, &constant.ty.sty) {
                 // if let Literal::Value{ ref value } = constant.literal {
                     // println!("literal");
                     // if let &ConstVal::Function(def_id) = value {
@@ -556,13 +982,17 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
                     // println!("XXX: {:?} {:?}", constant.ty, constant.literal);
                     // println!("{:?}", def_id);
                     // self.map.map.get(def_id);
-                    OpCode::LoadFunc(def_id.clone())
+                    let substs = self.monomorphize(&substs);
+                    match resolve::object_method_index(self.tcx, def_id, substs) {
+                        Some(idx) => OpCode::VIRTUAL(idx),
+                        None => OpCode::LoadFunc(resolve::resolve_item(self.tcx, def_id, substs)),
+                    }
                     // } else {
                         // OpCode::TODO("const literal item")
                     // }
                 } else {
                     // OpCode::Const(constant.clone())
-                    self.unpack_const(&constant.literal)
+                    self.unpack_const(constant)
                 }
             }
         };
@@ -597,7 +1027,8 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
             &Lvalue::Var(n) => OpCode::Load(Var::Var, n.index()),
             &Lvalue::Temp(n) => OpCode::Load(Var::Tmp, n.index()),
             &Lvalue::Arg(n) => OpCode::Load(Var::Arg, n.index()),
-            &Lvalue::Static(def_id) => OpCode::Static(def_id),
+            &Lvalue::Static(def_id) => OpCode::Static(Instance::mono(self.tcx, def_id)),
+            &Lvalue::ReturnPointer => OpCode::Load(Var::Return, 0),
             &Lvalue::Projection(ref proj) => {
                 match proj.elem {
                     ProjectionElem::Deref => {
@@ -620,6 +1051,25 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
 
                         OpCode::GetIndex
                     },
+                    ProjectionElem::ConstantIndex{ offset, from_end, .. } => {
+                        let opcode = self.load_lvalue(&proj.base);
+                        self.opcodes.push(opcode);
+                        self.opcodes.push(OpCode::CONSTANT_INDEX(offset as usize, from_end));
+
+                        OpCode::GetIndex
+                    },
+                    // [a, rest.., b] binds rest to the elements in between
+                    ProjectionElem::Subslice{ from, to } => {
+                        let opcode = self.load_lvalue(&proj.base);
+                        self.opcodes.push(opcode);
+
+                        OpCode::SUBSLICE(from as usize, to as usize)
+                    },
+                    // The fields of an enum variant are laid out like a tuple,
+                    // thus the following Field projection can access them directly.
+                    ProjectionElem::Downcast(_adt_def, _variant) => {
+                        self.load_lvalue(&proj.base)
+                    },
                     _ => OpCode::TODO("Projection")
                 }
             },
@@ -627,43 +1077,309 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
         }
     }
 
-    fn unpack_const(&self, literal: &Literal) -> OpCode<'a> {
-        match *literal {
-            Literal::Value{ ref value } => {
-                use rustc_const_math::ConstInt::*;
-                if let ConstVal::Integral(ref boxed) = *value {
-                    match *boxed {
+    /// Closures, fn items and fn pointers are called through the `Fn*`
+    /// traits, e.g. `<[closure] as Fn<(A,)>>::call(&f, (a,))`. The traits
+    /// have no MIR, returns what the call dispatches to if `func` is such a
+    /// call, and whether the receiver is passed by value, i.e. to
+    /// `call_once`.
+    fn fn_trait_callee(&self, func: &Operand<'a>) -> Option<(FnTraitCallee<'a>, bool)> {
+        let (def_id, substs) = match *func {
+            Operand::Constant(ref constant) => match constant.literal {
+                Literal::Item{ def_id, substs } => (def_id, substs),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let trait_id = match self.tcx.trait_of_item(def_id) {
+            Some(trait_id) if resolve::is_fn_trait(self.tcx, trait_id) => trait_id,
+            _ => return None,
+        };
 
-                         U8(u) => OpCode::UnsignedInteger(u as u64),
-                        U16(u) => OpCode::UnsignedInteger(u as u64),
-                        U32(u) => OpCode::UnsignedInteger(u as u64),
-                        U64(u) => OpCode::UnsignedInteger(u),
+        let substs = self.monomorphize(&substs);
+        let callee = match substs.type_at(0).sty {
+            TyClosure(closure_id, closure_substs) => {
+                FnTraitCallee::Closure(Instance::new(closure_id, closure_substs.func_substs))
+            },
+            TyFnDef(fn_id, fn_substs, _) => {
+                FnTraitCallee::FnItem(resolve::resolve_item(self.tcx, fn_id, fn_substs))
+            },
+            TyFnPtr(..) => FnTraitCallee::FnPtr,
+            TyTrait(..) => match resolve::object_method_index(self.tcx, def_id, substs) {
+                Some(idx) => FnTraitCallee::Object(idx),
+                None => return None,
+            },
+            _ => return None,
+        };
+        Some((callee, Some(trait_id) == self.tcx.lang_items.fn_once_trait()))
+    }
 
-                         I8(i) => OpCode::SignedInteger(i as i64),
-                        I16(i) => OpCode::SignedInteger(i as i64),
-                        I32(i) => OpCode::SignedInteger(i as i64),
-                        I64(i) => OpCode::SignedInteger(i),
+    // Only the body of a `FnOnce` closure takes its environment by value. An
+    // `Fn` or `FnMut` closure which is passed to `call_once` is borrowed for
+    // its body, a reference to a `FnOnce` closure is dereferenced.
+    fn closure_receiver(&mut self, receiver: &Operand<'a>, closure: Instance<'a>, by_value: bool) {
+        let kind = self.tcx.closure_kind(closure.def_id);
+        match (by_value, kind, receiver) {
+            (true, ClosureKind::FnOnce, _) | (false, ClosureKind::Fn, _) |
+            (false, ClosureKind::FnMut, _) => self.rvalue_operand(receiver),
 
-                        Usize(Us32(us32)) => OpCode::Usize(us32 as usize),
-                        Usize(Us64(us64)) => OpCode::Usize(us64 as usize),
+            (true, _, &Operand::Consume(ref lvalue)) => {
+                let opcode = self.load_lvalue(lvalue);
+                self.opcodes.push(opcode);
+                let borrow = if kind == ClosureKind::Fn { BorrowKind::Shared } else { BorrowKind::Mut };
+                self.opcodes.push(OpCode::BORROW(borrow));
+            },
+            (true, _, _) => self.opcodes.push(OpCode::TODO("borrow of a constant closure")),
 
-                        _ => panic!(format!("{:?}", boxed)),
-                    }
-                } else if let ConstVal::Bool(b) = *value {
-                    OpCode::Bool(b)
-                } else {
-                    unimplemented!();
+            (false, ClosureKind::FnOnce, _) => {
+                self.rvalue_operand(receiver);
+                self.opcodes.push(OpCode::DEREF);
+            },
+        }
+    }
+
+    /// Some library types are implemented by the interpreter instead of
+    /// running their MIR: a `&str` is a single value and not backed by bytes
+    /// in guest memory and a `Vec` is a box of an array. Returns the opcodes
+    /// which replace a call of a supported method.
+    fn native_method(&self, func: &Operand<'a>, args: &[Operand<'a>]) -> Option<Vec<OpCode<'a>>> {
+        let instance = match *func {
+            Operand::Constant(ref constant) => match constant.literal {
+                Literal::Item{ def_id, substs } => {
+                    resolve::resolve_item(self.tcx, def_id, self.monomorphize(&substs))
+                },
+                _ => return None,
+            },
+            _ => return None,
+        };
+        if let Some(opcodes) = self.panic_fn(instance.def_id) {
+            return Some(opcodes);
+        }
+        if let Some(opcodes) = self.intrinsic(instance.def_id) {
+            return Some(opcodes);
+        }
+
+        let impl_id = match self.tcx.impl_of_method(instance.def_id) {
+            Some(impl_id) => impl_id,
+            None => return None,
+        };
+        let self_ty = self.tcx.lookup_item_type(impl_id).ty;
+        let name = self.tcx.item_name(instance.def_id).as_str();
+
+        match self_ty.sty {
+            TyStr => return self.str_method(&name).map(|opcode| vec![opcode]),
+            // vec![..] boxes an array and turns it into a Vec
+            TySlice(_) if &*name == "into_vec" => return Some(vec![OpCode::VEC_FROM_BOX]),
+            TySlice(_) if &*name == "len" => return Some(vec![OpCode::Len]),
+            TySlice(_) if &*name == "index" || &*name == "index_mut" => {
+                return self.range_kind(args[1].ty(self.mir, self.tcx)).map(|kind| {
+                    vec![OpCode::SLICE_RANGE(kind)]
+                });
+            },
+            _ => {},
+        }
+
+        // e.g. `impl IntoIterator for &Vec<T>`
+        let self_ty = match self_ty.builtin_deref(true, LvaluePreference::NoPreference) {
+            Some(pointee) => pointee.ty,
+            None => self_ty,
+        };
+        let adt_def = match self_ty.ty_adt_def() {
+            Some(adt_def) => adt_def,
+            None => return None,
+        };
+        // the full path, there are other types named `IntoIter` or `Iter`
+        let adt_path = self.tcx.absolute_item_path_str(adt_def.did);
+
+        let opcodes = match (&*adt_path, &*name) {
+            ("collections::vec::Vec", "new") => vec![OpCode::VEC_NEW],
+            // the capacity is not needed
+            ("collections::vec::Vec", "with_capacity") => vec![OpCode::Pop, OpCode::VEC_NEW],
+            ("collections::vec::Vec", "push") => vec![OpCode::VEC_PUSH],
+            ("collections::vec::Vec", "pop") => vec![OpCode::VEC_POP],
+            ("collections::vec::Vec", "len") => vec![OpCode::VEC_LEN],
+            ("collections::vec::Vec", "into_iter") => vec![OpCode::VEC_ITER],
+            ("collections::vec::Vec", "deref") |
+            ("collections::vec::Vec", "deref_mut") => vec![OpCode::VEC_SLICE],
+            ("collections::vec::Vec", "as_ptr") |
+            ("collections::vec::Vec", "as_mut_ptr") => {
+                vec![OpCode::VEC_SLICE, OpCode::CAST(CastTarget::Ptr)]
+            },
+
+            // indexing with ranges results in slices
+            ("collections::vec::Vec", "index") | ("collections::vec::Vec", "index_mut") => {
+                let index_ty = args[1].ty(self.mir, self.tcx);
+                match self.monomorphize(&index_ty).sty {
+                    TyUint(UintTy::Us) => vec![OpCode::VEC_INDEX],
+                    _ => match self.range_kind(index_ty) {
+                        Some(kind) => vec![OpCode::SLICE_RANGE(kind)],
+                        None => return None,
+                    },
                 }
             },
-            Literal::Item{def_id: _, ..} => {
-                //let x = &42; will generate a reference to a static variable
-                // println!("{:?}", def_id);
-                unimplemented!()
+
+            ("collections::vec::IntoIter", "next") |
+            ("core::slice::Iter", "next") |
+            ("core::slice::IterMut", "next") => vec![OpCode::ITER_NEXT],
+
+            _ => return None,
+        };
+        Some(opcodes)
+    }
+
+    // `panic!` and `unreachable!` end up in one of these. Formatting the
+    // message needs more of libstd than the interpreter supports, so they
+    // raise the guest panic themselves.
+    fn panic_fn(&self, def_id: DefId) -> Option<Vec<OpCode<'a>>> {
+        let opcodes = match &*self.tcx.absolute_item_path_str(def_id) {
+            // (msg, file_line)
+            "std::panicking::begin_panic" => vec![OpCode::Pop, OpCode::PANIC],
+            // (&fmt::Arguments, file_line) and (fmt::Arguments, file_line)
+            "std::panicking::begin_panic_fmt" |
+            "core::panicking::panic_fmt" => vec![OpCode::Pop, OpCode::PANIC_FMT],
+            // &(msg, file, line)
+            "core::panicking::panic" => vec![OpCode::DEREF, OpCode::TUPLE_GET(0), OpCode::PANIC],
+            _ => return None,
+        };
+        Some(opcodes)
+    }
+
+    // Intrinsics have no MIR. `ptr::read` and `ptr::write` are implemented
+    // with the ones for copying and initializing memory.
+    fn intrinsic(&self, def_id: DefId) -> Option<Vec<OpCode<'a>>> {
+        match self.tcx.lookup_item_type(def_id).ty.sty {
+            TyFnDef(_, _, ref fn_ty) if fn_ty.abi == Abi::RustIntrinsic => {},
+            _ => return None,
+        }
+
+        let opcodes = match &*self.tcx.item_name(def_id).as_str() {
+            "offset" | "arith_offset" => vec![OpCode::PTR_OFFSET],
+            "copy" | "copy_nonoverlapping" => vec![OpCode::PTR_COPY],
+            "move_val_init" => vec![OpCode::PTR_WRITE],
+            "uninit" => vec![OpCode::UNINIT],
+            // the value isn't dropped
+            "forget" => vec![OpCode::Pop, OpCode::TUPLE(0)],
+            // fails when it's executed, the call may never be reached
+            name => vec![OpCode::TODO_S(format!("intrinsic {}", name))],
+        };
+        Some(opcodes)
+    }
+
+    fn range_kind(&self, ty: Ty<'a>) -> Option<RangeKind> {
+        let adt_def = match self.monomorphize(&ty).ty_adt_def() {
+            Some(adt_def) => adt_def,
+            None => return None,
+        };
+        if &*self.tcx.crate_name(adt_def.did.krate) != "core" {
+            return None;
+        }
+
+        match &*self.tcx.item_name(adt_def.did).as_str() {
+            "Range" => Some(RangeKind::Range),
+            "RangeFrom" => Some(RangeKind::RangeFrom),
+            "RangeTo" => Some(RangeKind::RangeTo),
+            "RangeFull" => Some(RangeKind::RangeFull),
+            _ => None,
+        }
+    }
+
+    fn str_method(&self, name: &str) -> Option<OpCode<'a>> {
+        match name {
+            "len" => Some(OpCode::Len),
+            // the string stands in for the slice of its bytes
+            "as_bytes" => Some(OpCode::Use),
+            "eq" => Some(OpCode::BINOP(BinOp::Eq)),
+            "ne" => Some(OpCode::BINOP(BinOp::Ne)),
+            "lt" => Some(OpCode::BINOP(BinOp::Lt)),
+            "le" => Some(OpCode::BINOP(BinOp::Le)),
+            "gt" => Some(OpCode::BINOP(BinOp::Gt)),
+            "ge" => Some(OpCode::BINOP(BinOp::Ge)),
+            _ => None,
+        }
+    }
+
+    // &[T; n] -> &[T] and &T -> &Trait, the same for Box. Other coercions,
+    // e.g. of a struct with an unsized field, are reported as a TODO_S.
+    fn unsize_metadata(&self, source: Ty<'a>, target: Ty<'a>) -> Result<Metadata<'a>, String> {
+        let pointee = |ty: Ty<'a>| {
+            match ty.builtin_deref(true, LvaluePreference::NoPreference) {
+                Some(mt) => Ok(mt.ty),
+                None => Err(format!("unsize of {:?}", ty)),
+            }
+        };
+
+        let (source, target) = (try!(pointee(source)), try!(pointee(target)));
+        match (&source.sty, &target.sty) {
+            (&TyArray(_, len), &TySlice(_)) => Ok(Metadata::Len(len)),
+            (_, &TyTrait(ref data)) => {
+                let trait_ref = data.principal.with_self_ty(self.tcx, source);
+                Ok(Metadata::Vtable(Rc::new(resolve::vtable_methods(self.tcx, trait_ref))))
             },
-            Literal::Promoted{index} => {
-                // OpCode::UnsignedInteger
-                OpCode::Usize(index.index())
+            _ => Err(format!("unsize from {:?} to {:?}", source, target)),
+        }
+    }
+
+    fn cast_target(&self, ty: Ty) -> Result<CastTarget, String> {
+        match ty.sty {
+            TyInt(int_ty) => Ok(CastTarget::Int(int_ty)),
+            TyUint(uint_ty) => Ok(CastTarget::Uint(uint_ty)),
+            TyFloat(float_ty) => Ok(CastTarget::Float(float_ty)),
+            TyChar => Ok(CastTarget::Char),
+            TyRawPtr(ref mt) => match mt.ty.sty {
+                TySlice(_) | TyStr | TyTrait(_) => Ok(CastTarget::FatPtr),
+                _ => Ok(CastTarget::Ptr),
             },
+            _ => Err(format!("cast to {:?}", ty)),
+        }
+    }
+
+    // `ty` is the type of the constant, float constants don't carry it
+    fn unpack_const_val(&self, value: &ConstVal, ty: Ty<'a>) -> OpCode<'a> {
+        use rustc_const_math::ConstInt::*;
+        if let ConstVal::Integral(ref boxed) = *value {
+            match *boxed {
+
+                 U8(u) => OpCode::UnsignedInteger(u as u64, UintTy::U8),
+                U16(u) => OpCode::UnsignedInteger(u as u64, UintTy::U16),
+                U32(u) => OpCode::UnsignedInteger(u as u64, UintTy::U32),
+                U64(u) => OpCode::UnsignedInteger(u, UintTy::U64),
+
+                 I8(i) => OpCode::SignedInteger(i as i64, IntTy::I8),
+                I16(i) => OpCode::SignedInteger(i as i64, IntTy::I16),
+                I32(i) => OpCode::SignedInteger(i as i64, IntTy::I32),
+                I64(i) => OpCode::SignedInteger(i, IntTy::I64),
+
+                Isize(Is32(is32)) => OpCode::SignedInteger(is32 as i64, IntTy::Is),
+                Isize(Is64(is64)) => OpCode::SignedInteger(is64, IntTy::Is),
+
+                Usize(Us32(us32)) => OpCode::Usize(us32 as usize),
+                Usize(Us64(us64)) => OpCode::Usize(us64 as usize),
+
+                _ => OpCode::TODO_S(format!("integer constant {:?}", boxed)),
+            }
+        } else if let ConstVal::Bool(b) = *value {
+            OpCode::Bool(b)
+        } else if let ConstVal::Char(c) = *value {
+            OpCode::Char(c)
+        } else if let ConstVal::Str(ref s) = *value {
+            OpCode::Str(s.clone())
+        } else if let ConstVal::Float(f) = *value {
+            match self.monomorphize(&ty).sty {
+                TyFloat(float_ty) => OpCode::Float(f, float_ty),
+                ref sty => OpCode::TODO_S(format!("float constant of type {:?}", sty)),
+            }
+        } else {
+            OpCode::TODO_S(format!("constant {:?}", value))
+        }
+    }
+
+    fn unpack_const(&self, constant: &Constant<'a>) -> OpCode<'a> {
+        match constant.literal {
+            Literal::Value{ ref value } => self.unpack_const_val(value, constant.ty),
+            // const items, functions are loaded in rvalue_operand
+            Literal::Item{ def_id, substs } => {
+                OpCode::LoadConst(Instance::new(def_id, self.monomorphize(&substs)))
+            },
+            Literal::Promoted{index} => OpCode::LoadPromoted(self.instance, index.index()),
         }
     }
 
@@ -677,12 +1393,12 @@ impl<'a, 'tcx> BlockGen<'a, 'tcx> {
 }
 
 
-pub fn generate_bytecode<'a, 'tcx>(context: &'a Context<'a, 'tcx>) -> (Program<'a, 'tcx>, DefId, BTreeMap<DefId, String>) {
+pub fn generate_bytecode<'a, 'tcx>(context: &'a Context<'a, 'tcx>) -> (Program<'a, 'tcx>, Instance<'a>, BTreeMap<DefId, String>) {
 
     //map krate num -> node id
     let mut program = Program::new(context);
     // let mut build_ins: BTreeMap<u32, BTreeMap<u32, &'a InternedString>> = BTreeMap::new();
-    let mut main: Option<DefId> = None;
+    let mut main: Option<Instance> = None;
 
     let mut internals= BTreeMap::new();
 
@@ -704,15 +1420,21 @@ pub fn generate_bytecode<'a, 'tcx>(context: &'a Context<'a, 'tcx>) -> (Program<'
                 // }
                 // let blocks = optimize_blocks(&collector.blocks, func_mir);
 
-                let blocks: Function = context.mir_to_bytecode(func_mir);
+                // generic functions are compiled by get_func when they are
+                // called, with the substs of the call
+                let generics = context.tcx.lookup_generics(def_index);
+                if generics.parent_types != 0 || !generics.types.is_empty() {
+                    continue;
+                }
 
-                program.krates.insert(def_index, Rc::new(blocks));
+                let instance = Instance::mono(context.tcx, def_index);
+                program.load(instance, func_mir);
 
                 if item.name.as_str().starts_with("__") {
                     let s = item.name.as_str()[2..].to_string();
                     internals.insert(def_index, s);
                 } else if def_index.krate == 0 && item.name.as_str() == "main" {
-                    main = Some(def_index);
+                    main = Some(instance);
                 }
         }
         // println!("{:?}", keys);
@@ -0,0 +1,164 @@
+// Resolution of trait methods to the implementation which is used for a
+// given instantiation. This follows what trans does for static dispatch.
+
+use rustc::hir::def_id::DefId;
+use rustc::traits::{self, Reveal};
+use rustc::ty::{self, TyCtxt, TypeFoldable};
+use rustc::ty::subst::Substs;
+use rustc::ty::TypeVariants::{TyTrait, TyAdt, TyClosure};
+
+use syntax::ast;
+use syntax::codemap::DUMMY_SP;
+
+use mossc::{Instance, Vtable};
+
+/// Resolves `def_id` instantiated with `substs` to the function which is
+/// actually called. Trait methods are resolved to the method of the impl,
+/// every other function is returned unchanged. So is a method which can't be
+/// resolved, it has no MIR and fails when it's called.
+pub fn resolve_item<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                              def_id: DefId,
+                              substs: &'tcx Substs<'tcx>) -> Instance<'tcx> {
+    let trait_id = match tcx.trait_of_item(def_id) {
+        Some(trait_id) => trait_id,
+        None => return Instance::new(def_id, substs),
+    };
+
+    // can't select an impl as long as there are type parameters left
+    if substs.has_param_types() {
+        return Instance::new(def_id, substs);
+    }
+
+    let trait_ref = ty::Binder(ty::TraitRef::from_method(tcx, trait_id, substs));
+    match fulfill_obligation(tcx, trait_ref) {
+        Some(traits::VtableImpl(vtable_impl)) => {
+            let name = tcx.item_name(def_id);
+            impl_method(tcx, substs, vtable_impl.impl_def_id, vtable_impl.substs, name)
+                .unwrap_or_else(|| Instance::new(def_id, substs))
+        },
+
+        // closures, builtin impls and trait objects have no impl to resolve to
+        _ => Instance::new(def_id, substs),
+    }
+}
+
+/// If `def_id` is a trait method called on a trait object, returns the index
+/// of the method in the object's vtable.
+pub fn object_method_index<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                     def_id: DefId,
+                                     substs: &'tcx Substs<'tcx>) -> Option<usize> {
+    let trait_id = match tcx.trait_of_item(def_id) {
+        Some(trait_id) => trait_id,
+        None => return None,
+    };
+
+    if let TyTrait(..) = substs.type_at(0).sty {
+        let trait_ref = ty::Binder(ty::TraitRef::from_method(tcx, trait_id, substs));
+        if let Some(traits::VtableObject(ref data)) = fulfill_obligation(tcx, trait_ref) {
+            return Some(traits::get_vtable_index_of_object_method(tcx, data, def_id));
+        }
+    }
+    None
+}
+
+/// Whether `trait_id` is one of `Fn`, `FnMut` and `FnOnce`.
+pub fn is_fn_trait<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>, trait_id: DefId) -> bool {
+    let lang_items = &tcx.lang_items;
+    [lang_items.fn_trait(), lang_items.fn_mut_trait(), lang_items.fn_once_trait()]
+        .contains(&Some(trait_id))
+}
+
+/// Builds the vtable of `trait_ref`, where the self type is the concrete type
+/// behind the trait object. Entries are in the order rustc assigns vtable
+/// indices: the methods of the trait and all its supertraits. Methods which
+/// can't be called on an object have no entry, neither do the `Fn*` methods
+/// of fn items and pointers. The destructor of the self type is the drop glue
+/// of the trait object.
+pub fn vtable_methods<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                trait_ref: ty::PolyTraitRef<'tcx>) -> Vtable<'tcx> {
+    let mut methods = Vec::new();
+
+    for trait_ref in traits::supertraits(tcx, trait_ref) {
+        let trait_ref = tcx.erase_late_bound_regions(&trait_ref);
+        let fn_trait = is_fn_trait(tcx, trait_ref.def_id);
+
+        for trait_item in tcx.trait_items(trait_ref.def_id).iter() {
+            if let ty::MethodTraitItem(ref method) = *trait_item {
+                if !method.generics.types.is_empty() {
+                    methods.push(None);
+                } else if fn_trait {
+                    // the caller spreads the tupled arguments for the closure
+                    // body, a fn item would also need the receiver removed
+                    methods.push(match trait_ref.self_ty().sty {
+                        TyClosure(closure_id, substs) => {
+                            Some(Instance::new(closure_id, substs.func_substs))
+                        },
+                        _ => None,
+                    });
+                } else {
+                    methods.push(Some(resolve_item(tcx, method.def_id, trait_ref.substs)));
+                }
+            }
+        }
+    }
+
+    let self_ty = tcx.erase_late_bound_regions(&trait_ref).self_ty();
+    let drop = match self_ty.sty {
+        TyAdt(adt_def, substs) => {
+            adt_def.destructor().map(|destructor| Instance::new(destructor, substs))
+        },
+        _ => None,
+    };
+
+    Vtable { methods: methods, drop: drop }
+}
+
+// None if no impl can be selected, e.g. because of an error or an ambiguity
+fn fulfill_obligation<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                                trait_ref: ty::PolyTraitRef<'tcx>) -> Option<traits::Vtable<'tcx, ()>> {
+    let trait_ref = tcx.erase_regions(&trait_ref);
+
+    tcx.infer_ctxt(None, None, Reveal::All).enter(|infcx| {
+        let mut selcx = traits::SelectionContext::new(&infcx);
+
+        let obligation = traits::Obligation::new(
+            traits::ObligationCause::misc(DUMMY_SP, ast::DUMMY_NODE_ID),
+            trait_ref.to_poly_trait_predicate());
+        let selection = match selcx.select(&obligation) {
+            Ok(Some(selection)) => selection,
+            _ => return None,
+        };
+
+        // nested obligations might still constrain the substitutions
+        let mut fulfill_cx = traits::FulfillmentContext::new();
+        let vtable = selection.map(|predicate| {
+            fulfill_cx.register_predicate_obligation(&infcx, predicate);
+        });
+        Some(infcx.drain_fulfillment_cx_or_panic(DUMMY_SP, &mut fulfill_cx, &vtable))
+    })
+}
+
+// Finds the method `name` in the impl, falling back to the default method of
+// the trait if the impl doesn't override it.
+fn impl_method<'a, 'tcx>(tcx: TyCtxt<'a, 'tcx, 'tcx>,
+                         substs: &'tcx Substs<'tcx>,
+                         impl_def_id: DefId,
+                         impl_substs: &'tcx Substs<'tcx>,
+                         name: ast::Name) -> Option<Instance<'tcx>> {
+    let trait_def_id = match tcx.trait_id_of_impl(impl_def_id) {
+        Some(trait_def_id) => trait_def_id,
+        None => return None,
+    };
+    let trait_def = tcx.lookup_trait_def(trait_def_id);
+
+    let node_item = match trait_def.ancestors(impl_def_id).fn_defs(tcx, name).next() {
+        Some(node_item) => node_item,
+        None => return None,
+    };
+    let substs = tcx.normalizing_infer_ctxt(Reveal::All).enter(|infcx| {
+        let substs = substs.rebase_onto(tcx, trait_def_id, impl_substs);
+        let substs = traits::translate_substs(&infcx, impl_def_id, substs, node_item.node);
+        tcx.lift(&substs)
+    });
+    substs.map(|substs| Instance::new(node_item.item.def_id, substs))
+}